@@ -3,10 +3,103 @@ CREATE TABLE IF NOT EXISTS users (
 	displayname TEXT NOT NULL,
 	username TEXT UNIQUE NOT NULL,
 	email TEXT UNIQUE NOT NULL,
-	password TEXT NOT NULL,
+	email_verified BOOLEAN NOT NULL DEFAULT FALSE,
+	password TEXT,
+	totp_secret TEXT,
+	totp_enabled BOOLEAN NOT NULL DEFAULT FALSE,
+	bio TEXT NOT NULL DEFAULT '',
+	deletion_scheduled_at BIGINT,
+	role TEXT NOT NULL DEFAULT 'user' CHECK (role IN ('user', 'admin')),
+	disabled BOOLEAN NOT NULL DEFAULT FALSE,
+	password_reset_required BOOLEAN NOT NULL DEFAULT FALSE,
 	created_at BIGINT NOT NULL
 );
 
+ALTER TABLE users ADD COLUMN IF NOT EXISTS email_verified BOOLEAN NOT NULL DEFAULT TRUE;
+
+ALTER TABLE users ALTER COLUMN email_verified SET DEFAULT FALSE;
+
+ALTER TABLE users ADD COLUMN IF NOT EXISTS totp_secret TEXT;
+
+ALTER TABLE users ADD COLUMN IF NOT EXISTS totp_enabled BOOLEAN NOT NULL DEFAULT FALSE;
+
+ALTER TABLE users ALTER COLUMN password DROP NOT NULL;
+
+ALTER TABLE users ADD COLUMN IF NOT EXISTS deletion_scheduled_at BIGINT;
+
+ALTER TABLE users ADD COLUMN IF NOT EXISTS bio TEXT NOT NULL DEFAULT '';
+
+ALTER TABLE users ADD COLUMN IF NOT EXISTS role TEXT NOT NULL DEFAULT 'user' CHECK (role IN ('user', 'admin'));
+
+ALTER TABLE users ADD COLUMN IF NOT EXISTS disabled BOOLEAN NOT NULL DEFAULT FALSE;
+
+ALTER TABLE users ADD COLUMN IF NOT EXISTS password_reset_required BOOLEAN NOT NULL DEFAULT FALSE;
+
+CREATE TABLE IF NOT EXISTS user_identities (
+	id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+	user_id UUID NOT NULL,
+	provider TEXT NOT NULL,
+	subject TEXT NOT NULL,
+	email TEXT,
+	created_at BIGINT NOT NULL,
+	UNIQUE (provider, subject),
+	UNIQUE (user_id, provider),
+	CONSTRAINT fk_user
+		FOREIGN KEY(user_id)
+			REFERENCES users(id)
+			ON DELETE CASCADE
+);
+
+CREATE TABLE IF NOT EXISTS auth_events (
+	id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+	user_id UUID,
+	kind TEXT NOT NULL,
+	identifier TEXT,
+	ip TEXT,
+	os TEXT NOT NULL,
+	browser TEXT NOT NULL,
+	actor_id UUID,
+	created_at BIGINT NOT NULL,
+	CONSTRAINT fk_user
+		FOREIGN KEY(user_id)
+			REFERENCES users(id)
+			ON DELETE SET NULL,
+	CONSTRAINT fk_actor
+		FOREIGN KEY(actor_id)
+			REFERENCES users(id)
+			ON DELETE SET NULL
+);
+
+ALTER TABLE auth_events ADD COLUMN IF NOT EXISTS actor_id UUID;
+
+CREATE INDEX IF NOT EXISTS auth_events_user_id_created_at ON auth_events (user_id, created_at);
+
+CREATE TABLE IF NOT EXISTS recovery_codes (
+	id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+	user_id UUID NOT NULL,
+	code_hash TEXT NOT NULL,
+	CONSTRAINT fk_user
+		FOREIGN KEY(user_id)
+			REFERENCES users(id)
+			ON DELETE CASCADE
+);
+
+CREATE TABLE IF NOT EXISTS access_tokens (
+	id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+	user_id UUID NOT NULL,
+	name TEXT NOT NULL,
+	token_hash TEXT UNIQUE NOT NULL,
+	scopes TEXT[] NOT NULL,
+	expires_at BIGINT,
+	last_used_at BIGINT,
+	revoked_at BIGINT,
+	created_at BIGINT NOT NULL,
+	CONSTRAINT fk_user
+		FOREIGN KEY(user_id)
+			REFERENCES users(id)
+			ON DELETE CASCADE
+);
+
 CREATE TABLE IF NOT EXISTS categories (
 	id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
 	user_id UUID NOT NULL,
@@ -18,6 +111,7 @@ CREATE TABLE IF NOT EXISTS categories (
 	CONSTRAINT fk_user
 		FOREIGN KEY(user_id)
 			REFERENCES users(id)
+			ON DELETE CASCADE
 );
 
 CREATE TABLE IF NOT EXISTS todos (
@@ -32,20 +126,56 @@ CREATE TABLE IF NOT EXISTS todos (
 	updated_at BIGINT NOT NULL,
 	CONSTRAINT fk_user
 		FOREIGN KEY(user_id)
-			REFERENCES users(id),
+			REFERENCES users(id)
+			ON DELETE CASCADE,
 	CONSTRAINT fk_category
 		FOREIGN KEY(cat_id)
 			REFERENCES categories(id)
+			ON DELETE CASCADE
 );
 
+ALTER TABLE categories DROP CONSTRAINT IF EXISTS fk_user,
+	ADD CONSTRAINT fk_user FOREIGN KEY(user_id) REFERENCES users(id) ON DELETE CASCADE;
+
+ALTER TABLE todos DROP CONSTRAINT IF EXISTS fk_user,
+	ADD CONSTRAINT fk_user FOREIGN KEY(user_id) REFERENCES users(id) ON DELETE CASCADE;
+
+ALTER TABLE todos DROP CONSTRAINT IF EXISTS fk_category,
+	ADD CONSTRAINT fk_category FOREIGN KEY(cat_id) REFERENCES categories(id) ON DELETE CASCADE;
+
+ALTER TABLE auth_events DROP CONSTRAINT IF EXISTS fk_user,
+	ADD CONSTRAINT fk_user FOREIGN KEY(user_id) REFERENCES users(id) ON DELETE SET NULL;
+
+ALTER TABLE auth_events DROP CONSTRAINT IF EXISTS fk_actor,
+	ADD CONSTRAINT fk_actor FOREIGN KEY(actor_id) REFERENCES users(id) ON DELETE SET NULL;
+
+CREATE OR REPLACE FUNCTION set_updated_at() RETURNS TRIGGER AS $$
+BEGIN
+	NEW.updated_at := EXTRACT(EPOCH FROM NOW())::BIGINT;
+	RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
+
 DROP TRIGGER IF EXISTS tsvectorupdate ON categories;
 
 DROP TRIGGER IF EXISTS tsvectorupdate ON todos;
 
+DROP TRIGGER IF EXISTS updatedatupdate ON categories;
+
+DROP TRIGGER IF EXISTS updatedatupdate ON todos;
+
 CREATE TRIGGER tsvectorupdate BEFORE INSERT OR UPDATE
 ON categories FOR EACH ROW EXECUTE PROCEDURE
 tsvector_update_trigger(tsv, 'pg_catalog.english', name, description);
 
 CREATE TRIGGER tsvectorupdate BEFORE INSERT OR UPDATE
 ON todos FOR EACH ROW EXECUTE PROCEDURE
-tsvector_update_trigger(tsv, 'pg_catalog.english', title, description);
\ No newline at end of file
+tsvector_update_trigger(tsv, 'pg_catalog.english', title, description);
+
+CREATE TRIGGER updatedatupdate BEFORE UPDATE
+ON categories FOR EACH ROW EXECUTE PROCEDURE
+set_updated_at();
+
+CREATE TRIGGER updatedatupdate BEFORE UPDATE
+ON todos FOR EACH ROW EXECUTE PROCEDURE
+set_updated_at();
\ No newline at end of file
@@ -1,3 +1,4 @@
+use actix_web::http::StatusCode;
 use serde::Serialize;
 use std::{error, fmt};
 
@@ -6,22 +7,299 @@ use std::{error, fmt};
 pub struct AuthError {
     pub kind: &'static str,
     pub message: &'static str,
+    /// Status code of the response the error is sent back in
+    #[serde(skip)]
+    pub status: StatusCode,
+    /// Number of seconds the client should wait before trying again (if applicable)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub retry_after: Option<u64>,
 }
 
 const USERNAME_NOT_FOUND: AuthError = AuthError {
     kind: "UsernameNotFound",
     message: "User with specified username not found",
+    status: StatusCode::UNAUTHORIZED,
+    retry_after: None,
+};
+
+const EMAIL_NOT_FOUND: AuthError = AuthError {
+    kind: "EmailNotFound",
+    message: "User with specified email not found",
+    status: StatusCode::UNAUTHORIZED,
+    retry_after: None,
 };
 
 const INCORRECT_PASSWORD: AuthError = AuthError {
     kind: "IncorrectPassword",
     message: "Password is incorrect",
+    status: StatusCode::UNAUTHORIZED,
+    retry_after: None,
+};
+
+const MISSING_REFRESH_TOKEN: AuthError = AuthError {
+    kind: "MissingRefreshToken",
+    message: "No refresh token was provided",
+    status: StatusCode::UNAUTHORIZED,
+    retry_after: None,
+};
+
+const INVALID_REFRESH_TOKEN: AuthError = AuthError {
+    kind: "InvalidRefreshToken",
+    message: "Refresh token is invalid",
+    status: StatusCode::UNAUTHORIZED,
+    retry_after: None,
+};
+
+const MISSING_ACCESS_TOKEN: AuthError = AuthError {
+    kind: "MissingAccessToken",
+    message: "No access token was provided",
+    status: StatusCode::UNAUTHORIZED,
+    retry_after: None,
+};
+
+const INVALID_ACCESS_TOKEN: AuthError = AuthError {
+    kind: "InvalidAccessToken",
+    message: "Access token is invalid",
+    status: StatusCode::UNAUTHORIZED,
+    retry_after: None,
+};
+
+const ACCESS_TOKEN_EXPIRED: AuthError = AuthError {
+    kind: "AccessTokenExpired",
+    message: "Access token has expired, please refresh it",
+    status: StatusCode::UNAUTHORIZED,
+    retry_after: None,
+};
+
+const SESSION_NOT_FOUND: AuthError = AuthError {
+    kind: "SessionNotFound",
+    message: "Session with specified id not found",
+    status: StatusCode::NOT_FOUND,
+    retry_after: None,
+};
+
+const REFRESH_TOKEN_REUSED: AuthError = AuthError {
+    kind: "RefreshTokenReused",
+    message: "Refresh token has already been used, the session has been revoked",
+    status: StatusCode::UNAUTHORIZED,
+    retry_after: None,
+};
+
+const SESSION_EXPIRED: AuthError = AuthError {
+    kind: "SessionExpired",
+    message: "Session has expired, please log in again",
+    status: StatusCode::UNAUTHORIZED,
+    retry_after: None,
+};
+
+const INVALID_VERIFICATION_TOKEN: AuthError = AuthError {
+    kind: "InvalidVerificationToken",
+    message: "Verification token is invalid, expired or has already been used",
+    status: StatusCode::UNAUTHORIZED,
+    retry_after: None,
+};
+
+const EMAIL_NOT_VERIFIED: AuthError = AuthError {
+    kind: "EmailNotVerified",
+    message: "Email must be verified before this can be accessed",
+    status: StatusCode::FORBIDDEN,
+    retry_after: None,
+};
+
+const INVALID_RESET_TOKEN: AuthError = AuthError {
+    kind: "InvalidResetToken",
+    message: "Password reset token is invalid, expired or has already been used",
+    status: StatusCode::UNAUTHORIZED,
+    retry_after: None,
+};
+
+const INVALID_CHALLENGE: AuthError = AuthError {
+    kind: "InvalidChallenge",
+    message: "Login challenge is invalid or has expired, please log in again",
+    status: StatusCode::UNAUTHORIZED,
+    retry_after: None,
+};
+
+const INVALID_TWO_FACTOR_CODE: AuthError = AuthError {
+    kind: "InvalidTwoFactorCode",
+    message: "Two-factor authentication code is incorrect",
+    status: StatusCode::UNAUTHORIZED,
+    retry_after: None,
+};
+
+const TWO_FACTOR_ALREADY_ENABLED: AuthError = AuthError {
+    kind: "TwoFactorAlreadyEnabled",
+    message: "Two-factor authentication is already enabled",
+    status: StatusCode::CONFLICT,
+    retry_after: None,
+};
+
+const TWO_FACTOR_NOT_ENABLED: AuthError = AuthError {
+    kind: "TwoFactorNotEnabled",
+    message: "Two-factor authentication is not enabled",
+    status: StatusCode::CONFLICT,
+    retry_after: None,
+};
+
+const INSUFFICIENT_SCOPE: AuthError = AuthError {
+    kind: "InsufficientScope",
+    message: "Access token doesn't have the scope needed for this route",
+    status: StatusCode::FORBIDDEN,
+    retry_after: None,
+};
+
+const ACCESS_TOKEN_NOT_FOUND: AuthError = AuthError {
+    kind: "AccessTokenNotFound",
+    message: "Personal access token doesn't exist or has already been revoked",
+    status: StatusCode::NOT_FOUND,
+    retry_after: None,
+};
+
+const OIDC_PROVIDER_NOT_FOUND: AuthError = AuthError {
+    kind: "OidcProviderNotFound",
+    message: "No identity provider with that name is configured",
+    status: StatusCode::NOT_FOUND,
+    retry_after: None,
+};
+
+const INVALID_OIDC_STATE: AuthError = AuthError {
+    kind: "InvalidOidcState",
+    message: "Sign in request is invalid or has expired, please try again",
+    status: StatusCode::UNAUTHORIZED,
+    retry_after: None,
+};
+
+const OIDC_LOGIN_FAILED: AuthError = AuthError {
+    kind: "OidcLoginFailed",
+    message: "Identity provider didn't return a valid identity",
+    status: StatusCode::UNAUTHORIZED,
+    retry_after: None,
+};
+
+const IDENTITY_ALREADY_LINKED: AuthError = AuthError {
+    kind: "IdentityAlreadyLinked",
+    message: "Identity is already linked to an account",
+    status: StatusCode::CONFLICT,
+    retry_after: None,
+};
+
+const IDENTITY_EMAIL_TAKEN: AuthError = AuthError {
+    kind: "IdentityEmailTaken",
+    message:
+        "An account with this email already exists, log in to it and link the provider instead",
+    status: StatusCode::CONFLICT,
+    retry_after: None,
+};
+
+const IDENTITY_NOT_FOUND: AuthError = AuthError {
+    kind: "IdentityNotFound",
+    message: "No identity from that provider is linked to the account",
+    status: StatusCode::NOT_FOUND,
+    retry_after: None,
+};
+
+const LAST_LOGIN_METHOD: AuthError = AuthError {
+    kind: "LastLoginMethod",
+    message: "Can't remove the account's only way of logging in",
+    status: StatusCode::CONFLICT,
+    retry_after: None,
+};
+
+const DELETION_NOT_SCHEDULED: AuthError = AuthError {
+    kind: "DeletionNotScheduled",
+    message: "Account isn't scheduled for deletion",
+    status: StatusCode::CONFLICT,
+    retry_after: None,
+};
+
+const USERNAME_TAKEN: AuthError = AuthError {
+    kind: "UsernameTaken",
+    message: "Username is already taken",
+    status: StatusCode::CONFLICT,
+    retry_after: None,
+};
+
+const INVALID_CSRF_TOKEN: AuthError = AuthError {
+    kind: "InvalidCsrfToken",
+    message: "CSRF token is missing or doesn't match the csrf_token cookie",
+    status: StatusCode::FORBIDDEN,
+    retry_after: None,
+};
+
+const ACCOUNT_DISABLED: AuthError = AuthError {
+    kind: "AccountDisabled",
+    message: "This account has been disabled",
+    status: StatusCode::FORBIDDEN,
+    retry_after: None,
+};
+
+const ADMIN_REQUIRED: AuthError = AuthError {
+    kind: "AdminRequired",
+    message: "Only admins can use this route",
+    status: StatusCode::FORBIDDEN,
+    retry_after: None,
+};
+
+const EMAIL_TAKEN: AuthError = AuthError {
+    kind: "EmailTaken",
+    message: "An account with this email already exists",
+    status: StatusCode::CONFLICT,
+    retry_after: None,
+};
+
+const PASSWORD_RESET_REQUIRED: AuthError = AuthError {
+    kind: "PasswordResetRequired",
+    message: "The password has been cleared by an admin, set a new one with a password reset first",
+    status: StatusCode::FORBIDDEN,
+    retry_after: None,
+};
+
+const TOO_MANY_ATTEMPTS: AuthError = AuthError {
+    kind: "TooManyAttempts",
+    message: "Too many failed login attempts, please try again later",
+    status: StatusCode::TOO_MANY_REQUESTS,
+    retry_after: None,
 };
 
+#[derive(Clone, Copy)]
 // The variants of an authentication error
 pub enum ErrorVariants {
     UsernameNotFound,
+    EmailNotFound,
     IncorrectPassword,
+    MissingRefreshToken,
+    InvalidRefreshToken,
+    SessionExpired,
+    SessionNotFound,
+    RefreshTokenReused,
+    MissingAccessToken,
+    InvalidAccessToken,
+    AccessTokenExpired,
+    InvalidVerificationToken,
+    EmailNotVerified,
+    InvalidResetToken,
+    InvalidChallenge,
+    InvalidTwoFactorCode,
+    TwoFactorAlreadyEnabled,
+    TwoFactorNotEnabled,
+    InsufficientScope,
+    AccessTokenNotFound,
+    OidcProviderNotFound,
+    InvalidOidcState,
+    OidcLoginFailed,
+    IdentityAlreadyLinked,
+    IdentityEmailTaken,
+    IdentityNotFound,
+    LastLoginMethod,
+    DeletionNotScheduled,
+    UsernameTaken,
+    EmailTaken,
+    InvalidCsrfToken,
+    AccountDisabled,
+    AdminRequired,
+    PasswordResetRequired,
+    /// Holds the number of seconds until the lockout ends
+    TooManyAttempts(u64),
 }
 
 impl ErrorVariants {
@@ -32,7 +310,43 @@ impl ErrorVariants {
                 kind: "AuthError",
                 body: super::ErrorCategories::AuthError(match self {
                     ErrorVariants::UsernameNotFound => USERNAME_NOT_FOUND,
+                    ErrorVariants::EmailNotFound => EMAIL_NOT_FOUND,
                     ErrorVariants::IncorrectPassword => INCORRECT_PASSWORD,
+                    ErrorVariants::MissingRefreshToken => MISSING_REFRESH_TOKEN,
+                    ErrorVariants::InvalidRefreshToken => INVALID_REFRESH_TOKEN,
+                    ErrorVariants::SessionExpired => SESSION_EXPIRED,
+                    ErrorVariants::SessionNotFound => SESSION_NOT_FOUND,
+                    ErrorVariants::RefreshTokenReused => REFRESH_TOKEN_REUSED,
+                    ErrorVariants::MissingAccessToken => MISSING_ACCESS_TOKEN,
+                    ErrorVariants::InvalidAccessToken => INVALID_ACCESS_TOKEN,
+                    ErrorVariants::AccessTokenExpired => ACCESS_TOKEN_EXPIRED,
+                    ErrorVariants::InvalidVerificationToken => INVALID_VERIFICATION_TOKEN,
+                    ErrorVariants::EmailNotVerified => EMAIL_NOT_VERIFIED,
+                    ErrorVariants::InvalidResetToken => INVALID_RESET_TOKEN,
+                    ErrorVariants::InvalidChallenge => INVALID_CHALLENGE,
+                    ErrorVariants::InvalidTwoFactorCode => INVALID_TWO_FACTOR_CODE,
+                    ErrorVariants::TwoFactorAlreadyEnabled => TWO_FACTOR_ALREADY_ENABLED,
+                    ErrorVariants::TwoFactorNotEnabled => TWO_FACTOR_NOT_ENABLED,
+                    ErrorVariants::InsufficientScope => INSUFFICIENT_SCOPE,
+                    ErrorVariants::AccessTokenNotFound => ACCESS_TOKEN_NOT_FOUND,
+                    ErrorVariants::OidcProviderNotFound => OIDC_PROVIDER_NOT_FOUND,
+                    ErrorVariants::InvalidOidcState => INVALID_OIDC_STATE,
+                    ErrorVariants::OidcLoginFailed => OIDC_LOGIN_FAILED,
+                    ErrorVariants::IdentityAlreadyLinked => IDENTITY_ALREADY_LINKED,
+                    ErrorVariants::IdentityEmailTaken => IDENTITY_EMAIL_TAKEN,
+                    ErrorVariants::IdentityNotFound => IDENTITY_NOT_FOUND,
+                    ErrorVariants::LastLoginMethod => LAST_LOGIN_METHOD,
+                    ErrorVariants::DeletionNotScheduled => DELETION_NOT_SCHEDULED,
+                    ErrorVariants::UsernameTaken => USERNAME_TAKEN,
+                    ErrorVariants::EmailTaken => EMAIL_TAKEN,
+                    ErrorVariants::InvalidCsrfToken => INVALID_CSRF_TOKEN,
+                    ErrorVariants::AccountDisabled => ACCOUNT_DISABLED,
+                    ErrorVariants::AdminRequired => ADMIN_REQUIRED,
+                    ErrorVariants::PasswordResetRequired => PASSWORD_RESET_REQUIRED,
+                    ErrorVariants::TooManyAttempts(retry_after) => AuthError {
+                        retry_after: Some(retry_after),
+                        ..TOO_MANY_ATTEMPTS
+                    },
                 }),
             },
         }
@@ -11,10 +11,22 @@ const DB_ERROR: InternalServerError = InternalServerError { kind: "DBError" };
 
 const AUTH_ERROR: InternalServerError = InternalServerError { kind: "AuthError" };
 
+const SESSION_STORE_ERROR: InternalServerError = InternalServerError {
+    kind: "SessionStoreError",
+};
+
+const OIDC_PROVIDER_ERROR: InternalServerError = InternalServerError {
+    kind: "OidcProviderError",
+};
+
+#[derive(Clone, Copy)]
 /// The variants of an internal server error
+#[allow(clippy::enum_variant_names)]
 pub enum ErrorVariants {
     DBError,
     AuthError,
+    SessionStoreError,
+    OidcProviderError,
 }
 
 impl ErrorVariants {
@@ -26,6 +38,8 @@ impl ErrorVariants {
                 body: super::ErrorCategories::InternalServerError(match self {
                     ErrorVariants::DBError => DB_ERROR,
                     ErrorVariants::AuthError => AUTH_ERROR,
+                    ErrorVariants::SessionStoreError => SESSION_STORE_ERROR,
+                    ErrorVariants::OidcProviderError => OIDC_PROVIDER_ERROR,
                 }),
             },
         }
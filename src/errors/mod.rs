@@ -1,17 +1,20 @@
+use actix_web::{http::StatusCode, HttpResponse, ResponseError};
 use serde::Serialize;
-use std::io::Write;
 
 pub mod auth;
 pub mod internal_server;
+pub mod resource;
 pub mod validation;
 
 #[derive(Serialize, Debug)]
 #[serde(untagged)]
+#[allow(clippy::enum_variant_names)]
 /// Represents all the different error categories
 pub enum ErrorCategories {
     ValidationError(validation::ValidationError),
     InternalServerError(internal_server::InternalServerError),
     AuthError(auth::AuthError),
+    ResourceError(resource::ResourceError),
 }
 
 /// The actual error
@@ -43,3 +46,40 @@ impl std::fmt::Display for Error {
 }
 
 impl std::error::Error for Error {}
+
+#[cfg(test)]
+impl Error {
+    /// The kind of error within its category (e.g. "IncorrectPassword"), or the field for
+    /// validation errors, so tests can check which error they got
+    pub fn variant(&self) -> &'static str {
+        match &self.error.body {
+            ErrorCategories::ValidationError(e) => e.field,
+            ErrorCategories::InternalServerError(e) => e.kind,
+            ErrorCategories::AuthError(e) => e.kind,
+            ErrorCategories::ResourceError(e) => e.kind,
+        }
+    }
+}
+
+impl ResponseError for Error {
+    fn status_code(&self) -> StatusCode {
+        match &self.error.body {
+            ErrorCategories::AuthError(e) => e.status,
+            ErrorCategories::ResourceError(_) => StatusCode::NOT_FOUND,
+            ErrorCategories::ValidationError(_) => StatusCode::BAD_REQUEST,
+            ErrorCategories::InternalServerError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        let mut res = HttpResponse::build(self.status_code());
+        if let ErrorCategories::AuthError(auth::AuthError {
+            retry_after: Some(retry_after),
+            ..
+        }) = &self.error.body
+        {
+            res.header("Retry-After", retry_after.to_string());
+        }
+        res.json(self)
+    }
+}
@@ -0,0 +1,61 @@
+use serde::Serialize;
+use std::{error, fmt};
+
+#[derive(Serialize, Debug)]
+/// An error for a resource (e.g. a category or user) that doesn't exist, or doesn't belong to the user
+pub struct ResourceError {
+    pub kind: &'static str,
+    pub message: &'static str,
+}
+
+const CATEGORY_NOT_FOUND: ResourceError = ResourceError {
+    kind: "CategoryNotFound",
+    message: "Category with specified id not found",
+};
+
+const TODO_NOT_FOUND: ResourceError = ResourceError {
+    kind: "TodoNotFound",
+    message: "Todo with specified id not found",
+};
+
+const USER_NOT_FOUND: ResourceError = ResourceError {
+    kind: "UserNotFound",
+    message: "User with specified id not found",
+};
+
+#[derive(Clone, Copy)]
+/// The variants of a resource error
+#[allow(clippy::enum_variant_names)]
+pub enum ErrorVariants {
+    CategoryNotFound,
+    TodoNotFound,
+    UserNotFound,
+}
+
+impl ErrorVariants {
+    /// Wraps error variant in the `errors::Error` struct
+    pub fn to_error(self) -> super::Error {
+        super::Error {
+            error: super::ApplicationError {
+                kind: "ResourceError",
+                body: super::ErrorCategories::ResourceError(match self {
+                    ErrorVariants::CategoryNotFound => CATEGORY_NOT_FOUND,
+                    ErrorVariants::TodoNotFound => TODO_NOT_FOUND,
+                    ErrorVariants::UserNotFound => USER_NOT_FOUND,
+                }),
+            },
+        }
+    }
+}
+
+impl fmt::Display for ResourceError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "ResourceError {{ kind: {}, message: \"{}\" }}",
+            self.kind, self.message
+        )
+    }
+}
+
+impl error::Error for ResourceError {}
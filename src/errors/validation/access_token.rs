@@ -0,0 +1,42 @@
+type ValidationError = super::ValidationError;
+
+const NAME_LENGTH: ValidationError = ValidationError {
+    field: "name",
+    message: "name must be 1 to 64 characters in length",
+};
+
+const SCOPES_EMPTY: ValidationError = ValidationError {
+    field: "scopes",
+    message: "scopes must contain at least one scope",
+};
+
+const SCOPE_INVALID: ValidationError = ValidationError {
+    field: "scopes",
+    message: "scopes must only contain: todos:read, todos:write, categories:read, categories:write",
+};
+
+const EXPIRY_RANGE: ValidationError = ValidationError {
+    field: "expires_in_days",
+    message: "expires_in_days must be between 1 and 365",
+};
+
+#[derive(Clone, Copy)]
+/// The variants of a personal access token validation error
+pub enum ErrorVariants {
+    NameLength,
+    ScopesEmpty,
+    ScopeInvalid,
+    ExpiryRange,
+}
+
+impl ErrorVariants {
+    /// Construct validation error from error variant
+    pub fn to_validation_error(self) -> ValidationError {
+        match self {
+            ErrorVariants::NameLength => NAME_LENGTH,
+            ErrorVariants::ScopesEmpty => SCOPES_EMPTY,
+            ErrorVariants::ScopeInvalid => SCOPE_INVALID,
+            ErrorVariants::ExpiryRange => EXPIRY_RANGE,
+        }
+    }
+}
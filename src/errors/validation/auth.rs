@@ -10,6 +10,11 @@ const USERNAME_LENGTH: ValidationError = ValidationError {
     message: "username must be 3 to 128 characters in length",
 };
 
+const USERNAME_INVALID: ValidationError = ValidationError {
+    field: "username",
+    message: "username must not contain an @",
+};
+
 const EMAIL_LENGTH: ValidationError = ValidationError {
     field: "email",
     message: "email must be 6 to 256 characters in length",
@@ -20,18 +25,56 @@ const EMAIL_INVALID: ValidationError = ValidationError {
     message: "email is invalid",
 };
 
+const IDENTIFIER_USERNAME_LENGTH: ValidationError = ValidationError {
+    field: "identifier",
+    message: "identifier must be 3 to 128 characters in length when it is a username",
+};
+
+const IDENTIFIER_EMAIL_LENGTH: ValidationError = ValidationError {
+    field: "identifier",
+    message: "identifier must be 6 to 256 characters in length when it is an email",
+};
+
+const IDENTIFIER_EMAIL_INVALID: ValidationError = ValidationError {
+    field: "identifier",
+    message: "identifier is not a valid email",
+};
+
 const PASSWORD_WEAK: ValidationError = ValidationError {
     field: "password",
     message: "password must contain at least: 1 upper case letter, 1 lower case letter, 1 number or special character and must be between 8 and 128 characters in length",
 };
 
+const NEW_PASSWORD_WEAK: ValidationError = ValidationError {
+    field: "new_password",
+    message: "new_password must contain at least: 1 upper case letter, 1 lower case letter, 1 number or special character and must be between 8 and 128 characters in length",
+};
+
+const BIO_LENGTH: ValidationError = ValidationError {
+    field: "bio",
+    message: "bio must be at most 512 characters in length",
+};
+
+const DEVICE_NAME_LENGTH: ValidationError = ValidationError {
+    field: "device_name",
+    message: "device_name must be at most 64 characters in length",
+};
+
+#[derive(Clone, Copy)]
 /// The variants of an authentication validation error
 pub enum ErrorVariants {
     DisplaynameLength,
     UsernameLength,
+    UsernameInvalid,
     EmailLength,
     EmailInvalid,
+    IdentifierUsernameLength,
+    IdentifierEmailLength,
+    IdentifierEmailInvalid,
     PasswordWeak,
+    NewPasswordWeak,
+    BioLength,
+    DeviceNameLength,
 }
 
 impl ErrorVariants {
@@ -40,9 +83,16 @@ impl ErrorVariants {
         match self {
             ErrorVariants::DisplaynameLength => DISPLAYNAME_LENGTH,
             ErrorVariants::UsernameLength => USERNAME_LENGTH,
+            ErrorVariants::UsernameInvalid => USERNAME_INVALID,
             ErrorVariants::EmailLength => EMAIL_LENGTH,
             ErrorVariants::EmailInvalid => EMAIL_INVALID,
+            ErrorVariants::IdentifierUsernameLength => IDENTIFIER_USERNAME_LENGTH,
+            ErrorVariants::IdentifierEmailLength => IDENTIFIER_EMAIL_LENGTH,
+            ErrorVariants::IdentifierEmailInvalid => IDENTIFIER_EMAIL_INVALID,
             ErrorVariants::PasswordWeak => PASSWORD_WEAK,
+            ErrorVariants::NewPasswordWeak => NEW_PASSWORD_WEAK,
+            ErrorVariants::BioLength => BIO_LENGTH,
+            ErrorVariants::DeviceNameLength => DEVICE_NAME_LENGTH,
         }
     }
 }
@@ -0,0 +1,28 @@
+type ValidationError = super::ValidationError;
+
+const NAME_LENGTH: ValidationError = ValidationError {
+    field: "name",
+    message: "name must be 1 to 128 characters in length",
+};
+
+const DESCRIPTION_LENGTH: ValidationError = ValidationError {
+    field: "description",
+    message: "description must be at most 2048 characters in length",
+};
+
+#[derive(Clone, Copy)]
+/// The variants of a category validation error
+pub enum ErrorVariants {
+    NameLength,
+    DescriptionLength,
+}
+
+impl ErrorVariants {
+    /// Construct validation error from error variant
+    pub fn to_validation_error(self) -> ValidationError {
+        match self {
+            ErrorVariants::NameLength => NAME_LENGTH,
+            ErrorVariants::DescriptionLength => DESCRIPTION_LENGTH,
+        }
+    }
+}
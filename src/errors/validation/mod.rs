@@ -1,9 +1,12 @@
 use serde::Serialize;
 use std::{error, fmt};
 
+pub mod access_token;
 pub mod auth;
+pub mod category;
+pub mod todo;
 
-#[derive(Serialize, Debug)]
+#[derive(Serialize, Debug, Clone, Copy)]
 /// A validation error, with `field` being the field of the struct that validation failed on and `message` containing the requirements that were not satisfied
 pub struct ValidationError {
     pub field: &'static str,
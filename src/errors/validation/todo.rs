@@ -0,0 +1,28 @@
+type ValidationError = super::ValidationError;
+
+const TITLE_LENGTH: ValidationError = ValidationError {
+    field: "title",
+    message: "title must be 1 to 256 characters in length",
+};
+
+const DESCRIPTION_LENGTH: ValidationError = ValidationError {
+    field: "description",
+    message: "description must be at most 2048 characters in length",
+};
+
+#[derive(Clone, Copy)]
+/// The variants of a todo validation error
+pub enum ErrorVariants {
+    TitleLength,
+    DescriptionLength,
+}
+
+impl ErrorVariants {
+    /// Construct validation error from error variant
+    pub fn to_validation_error(self) -> ValidationError {
+        match self {
+            ErrorVariants::TitleLength => TITLE_LENGTH,
+            ErrorVariants::DescriptionLength => DESCRIPTION_LENGTH,
+        }
+    }
+}
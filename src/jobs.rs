@@ -0,0 +1,27 @@
+use crate::models::{session::UserSession, user::User};
+use std::time::Duration;
+
+/// How often accounts with a due scheduled deletion are deleted
+const DELETION_PURGE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// Periodically delete the accounts whose deletion grace period has run out (and their sessions)
+pub fn spawn_deletion_purge(db_pool: sqlx::PgPool, redis_pool: r2d2::Pool<redis::Client>) {
+    actix_web::rt::spawn(async move {
+        let mut interval = actix_web::rt::time::interval(DELETION_PURGE_INTERVAL);
+        loop {
+            interval.tick().await;
+            let ids = match User::delete_due(&db_pool).await {
+                Ok(ids) => ids,
+                Err(e) => {
+                    eprintln!("Failed to purge scheduled account deletions: {}", e);
+                    continue;
+                }
+            };
+            for id in ids {
+                if let Err(e) = UserSession::revoke_all(&redis_pool, id) {
+                    eprintln!("Failed to revoke sessions of deleted user {}: {}", id, e);
+                }
+            }
+        }
+    });
+}
@@ -0,0 +1,158 @@
+use anyhow::{anyhow, Result};
+use lettre::{
+    smtp::authentication::Credentials, ClientSecurity, EmailAddress, Envelope, SendableEmail,
+    SmtpClient, Transport,
+};
+use std::{
+    env, fs,
+    path::PathBuf,
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// A plain text email
+pub struct Mail {
+    pub to: String,
+    pub subject: String,
+    pub body: String,
+}
+
+impl Mail {
+    /// Render the email as an RFC 5322 message from `from`
+    fn to_message(&self, from: &str, message_id: &str) -> String {
+        format!(
+            "From: {}\r\nTo: {}\r\nSubject: {}\r\nMessage-ID: <{}>\r\nMIME-Version: 1.0\r\nContent-Type: text/plain; charset=utf-8\r\n\r\n{}\r\n",
+            from,
+            self.to,
+            self.subject,
+            message_id,
+            self.body.replace('\n', "\r\n")
+        )
+    }
+}
+
+/// Something that can deliver emails
+pub trait MailTransport: Send + Sync {
+    fn send(&self, mail: &Mail) -> Result<()>;
+}
+
+/// Delivers emails through an SMTP server
+pub struct SmtpMailTransport {
+    from: String,
+    host: String,
+    port: u16,
+    /// Whether to require `STARTTLS` (should only be turned off for local mail catchers)
+    tls: bool,
+    credentials: Option<(String, String)>,
+}
+
+impl MailTransport for SmtpMailTransport {
+    fn send(&self, mail: &Mail) -> Result<()> {
+        let client = if self.tls {
+            SmtpClient::new_simple(&self.host)?
+        } else {
+            SmtpClient::new((self.host.as_str(), self.port), ClientSecurity::None)?
+        };
+        let client = match &self.credentials {
+            Some((username, password)) => {
+                client.credentials(Credentials::new(username.clone(), password.clone()))
+            }
+            None => client,
+        };
+        let message_id = format!("{}@todoapi", uuid::Uuid::new_v4());
+        let envelope = Envelope::new(
+            Some(EmailAddress::new(self.from.clone())?),
+            vec![EmailAddress::new(mail.to.clone())?],
+        )?;
+        let email = SendableEmail::new(
+            envelope,
+            message_id.clone(),
+            mail.to_message(&self.from, &message_id).into_bytes(),
+        );
+        client
+            .transport()
+            .send(email)
+            .map(|_| ())
+            .map_err(|e| anyhow!(e))
+    }
+}
+
+/// Writes emails to files in a directory instead of sending them (for local development and
+/// testing)
+pub struct OutboxMailTransport {
+    from: String,
+    dir: PathBuf,
+}
+
+impl MailTransport for OutboxMailTransport {
+    fn send(&self, mail: &Mail) -> Result<()> {
+        fs::create_dir_all(&self.dir)?;
+        let message_id = format!("{}@todoapi", uuid::Uuid::new_v4());
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis();
+        let path = self.dir.join(format!("{}-{}.eml", timestamp, message_id));
+        fs::write(&path, mail.to_message(&self.from, &message_id))?;
+        Ok(())
+    }
+}
+
+/// Construct the mail transport selected by the "MAIL_TRANSPORT" env var ("smtp" or "outbox")
+pub fn from_env() -> Result<Arc<dyn MailTransport>> {
+    let from = env::var("MAIL_FROM").expect("MAIL_FROM env var unset");
+    match env::var("MAIL_TRANSPORT")
+        .expect("MAIL_TRANSPORT env var unset")
+        .as_str()
+    {
+        "smtp" => Ok(Arc::new(SmtpMailTransport {
+            from,
+            host: env::var("SMTP_HOST").expect("SMTP_HOST env var unset"),
+            port: env::var("SMTP_PORT")
+                .map(|p| p.parse().expect("SMTP_PORT must be a port number"))
+                .unwrap_or(587),
+            tls: env::var("SMTP_TLS").map(|t| t != "false").unwrap_or(true),
+            credentials: env::var("SMTP_USERNAME")
+                .ok()
+                .zip(env::var("SMTP_PASSWORD").ok()),
+        })),
+        "outbox" => Ok(Arc::new(OutboxMailTransport {
+            from,
+            dir: env::var("MAIL_OUTBOX_DIR")
+                .unwrap_or_else(|_| "outbox".to_string())
+                .into(),
+        })),
+        other => Err(anyhow!("Unknown MAIL_TRANSPORT \"{}\"", other)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_outbox_writes_each_email_to_a_file() {
+        let dir = env::temp_dir().join(format!("todoapi-outbox-{}", uuid::Uuid::new_v4()));
+        let outbox = OutboxMailTransport {
+            from: "todoapi@localhost".to_string(),
+            dir: dir.clone(),
+        };
+        let mail = Mail {
+            to: "johnd03@example.com".to_string(),
+            subject: "Verify your email".to_string(),
+            body: "Hi johnd03,\nfollow the link below".to_string(),
+        };
+        outbox.send(&mail).unwrap();
+        outbox.send(&mail).unwrap();
+
+        let files: Vec<PathBuf> = fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .collect();
+        assert_eq!(files.len(), 2);
+        let message = fs::read_to_string(&files[0]).unwrap();
+        assert!(message.starts_with(
+            "From: todoapi@localhost\r\nTo: johnd03@example.com\r\nSubject: Verify your email\r\n"
+        ));
+        assert!(message.ends_with("\r\n\r\nHi johnd03,\r\nfollow the link below\r\n"));
+        assert!(files.iter().all(|f| f.extension().unwrap() == "eml"));
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
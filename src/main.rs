@@ -1,10 +1,13 @@
 use actix_web::{middleware::Logger, web, App, HttpServer, Responder};
 use anyhow::Result;
-use dotenv;
+use middleware::csrf::Csrf;
 use std::env;
 
 mod errors;
 mod init;
+mod jobs;
+mod mail;
+mod middleware;
 mod models;
 mod routes;
 mod validation;
@@ -20,17 +23,97 @@ async fn main() -> Result<()> {
 
     let address = env::var("ADDRESS").expect("ADDRESS env var unset");
     let (db_pool, redis_pool) = init::init().await?;
+    let mailer = mail::from_env()?;
+    jobs::spawn_deletion_purge(db_pool.clone(), redis_pool.clone());
 
     HttpServer::new(move || {
         App::new()
             .data(db_pool.clone())
             .data(redis_pool.clone())
+            .app_data(web::Data::from(mailer.clone()))
+            .service(web::scope("/.well-known").service(routes::well_known::jwks))
             .service(
-                web::scope("/api").route("/", web::get().to(helo)).service(
-                    web::scope("/users/auth")
-                        .service(routes::auth::register)
-                        .service(routes::auth::login),
-                ),
+                web::scope("/api")
+                    .route("/", web::get().to(helo))
+                    .service(
+                        web::scope("/users/auth")
+                            .service(routes::auth::register)
+                            .service(routes::auth::login)
+                            .service(routes::auth::login_two_factor)
+                            .service(routes::auth::refresh)
+                            .service(routes::auth::sessions)
+                            .service(routes::auth::rename_session)
+                            .service(routes::auth::revoke_session)
+                            .service(routes::auth::logout)
+                            .service(routes::auth::logout_all)
+                            .service(routes::auth::resend_verification)
+                            .service(routes::auth::verify_email)
+                            .service(routes::auth::request_password_reset)
+                            .service(routes::auth::complete_password_reset)
+                            .service(routes::auth::change_password)
+                            .service(routes::auth::history)
+                            .service(
+                                web::scope("/2fa")
+                                    .wrap(Csrf)
+                                    .service(routes::two_factor::enroll)
+                                    .service(routes::two_factor::confirm)
+                                    .service(routes::two_factor::disable)
+                                    .service(routes::two_factor::regenerate_recovery_codes),
+                            )
+                            .service(
+                                web::scope("/tokens")
+                                    .wrap(Csrf)
+                                    .service(routes::tokens::list)
+                                    .service(routes::tokens::create)
+                                    .service(routes::tokens::revoke),
+                            )
+                            .service(
+                                web::scope("/oidc")
+                                    .wrap(Csrf)
+                                    .service(routes::oidc::identities)
+                                    .service(routes::oidc::login)
+                                    .service(routes::oidc::link)
+                                    .service(routes::oidc::callback)
+                                    .service(routes::oidc::unlink),
+                            ),
+                    )
+                    .service(
+                        web::scope("/admin/users")
+                            .wrap(Csrf)
+                            .service(routes::admin::list_users)
+                            .service(routes::admin::list_events)
+                            .service(routes::admin::get_user)
+                            .service(routes::admin::disable_user)
+                            .service(routes::admin::enable_user)
+                            .service(routes::admin::logout_user)
+                            .service(routes::admin::reset_user_password),
+                    )
+                    .service(
+                        web::scope("/categories")
+                            .wrap(Csrf)
+                            .service(routes::categories::get_all)
+                            .service(routes::categories::create)
+                            .service(routes::categories::get)
+                            .service(routes::categories::update)
+                            .service(routes::categories::delete)
+                            .service(
+                                web::scope("/{cat_id}/todos")
+                                    .service(routes::todos::get_all)
+                                    .service(routes::todos::create)
+                                    .service(routes::todos::get)
+                                    .service(routes::todos::toggle)
+                                    .service(routes::todos::update)
+                                    .service(routes::todos::delete),
+                            ),
+                    )
+                    .service(
+                        web::scope("/user")
+                            .wrap(Csrf)
+                            .service(routes::user::get_profile)
+                            .service(routes::user::update_profile)
+                            .service(routes::user::delete_account)
+                            .service(routes::user::cancel_deletion),
+                    ),
             )
             .wrap(Logger::default())
     })
@@ -0,0 +1,159 @@
+use crate::errors::auth;
+use crate::routes::helpers::auth::{CSRF_COOKIE, CSRF_HEADER};
+use actix_web::{
+    dev::{Service, ServiceRequest, ServiceResponse, Transform},
+    http::{header, Method},
+    Error, HttpMessage,
+};
+use std::{
+    future::{ready, Future, Ready},
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+/// Double-submit CSRF protection: state-changing requests that are authenticated with cookies
+/// must echo the "csrf_token" cookie back in the "X-CSRF-Token" header, which a cross-site page
+/// can't do since it can't read the cookie. Only the scopes and routes that act on a session are
+/// wrapped with it, the ones used before a client has a CSRF token (registration, login,
+/// password resets, ...) are left unwrapped so they work with stale cookies
+pub struct Csrf;
+
+impl<S, B> Transform<S> for Csrf
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = CsrfMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(CsrfMiddleware { service }))
+    }
+}
+
+pub struct CsrfMiddleware<S> {
+    service: S,
+}
+
+impl<S, B> Service for CsrfMiddleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: ServiceRequest) -> Self::Future {
+        if needs_check(&req) && !token_matches(&req) {
+            let err = auth::ErrorVariants::InvalidCsrfToken.to_error();
+            return Box::pin(async move { Err(err.into()) });
+        }
+        Box::pin(self.service.call(req))
+    }
+}
+
+/// Whether the request changes state using cookie credentials
+fn needs_check(req: &ServiceRequest) -> bool {
+    if matches!(*req.method(), Method::GET | Method::HEAD | Method::OPTIONS) {
+        return false;
+    }
+    // Personal access tokens aren't sent automatically by browsers
+    if req.headers().contains_key(header::AUTHORIZATION) {
+        return false;
+    }
+    req.cookie("access_token").is_some() || req.cookie("refresh_token").is_some()
+}
+
+/// Whether the "X-CSRF-Token" header matches the "csrf_token" cookie
+fn token_matches(req: &ServiceRequest) -> bool {
+    let cookie = match req.cookie(CSRF_COOKIE) {
+        Some(c) => c,
+        None => return false,
+    };
+    let header = match req.headers().get(CSRF_HEADER) {
+        Some(h) => h.as_bytes(),
+        None => return false,
+    };
+    let cookie = cookie.value().as_bytes();
+    // Compare in constant time so the token can't be guessed a byte at a time
+    !cookie.is_empty() && cookie.len() == header.len() && openssl::memcmp::eq(cookie, header)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{cookie::Cookie, http::StatusCode, post, test, web, App, HttpResponse};
+
+    #[post("/marked", wrap = "Csrf")]
+    async fn marked() -> HttpResponse {
+        HttpResponse::Ok().finish()
+    }
+
+    /// Send `req` to an app with a protected scope, a protected route and an unprotected route,
+    /// returning the status it's answered with
+    async fn status(req: test::TestRequest) -> StatusCode {
+        let mut app = test::init_service(
+            App::new()
+                .service(
+                    web::scope("/scope")
+                        .wrap(Csrf)
+                        .route("", web::to(HttpResponse::Ok)),
+                )
+                .service(marked)
+                .route("/open", web::post().to(HttpResponse::Ok)),
+        )
+        .await;
+        match app.call(req.to_request()).await {
+            Ok(res) => res.status(),
+            Err(e) => e.as_response_error().status_code(),
+        }
+    }
+
+    fn with_session(req: test::TestRequest) -> test::TestRequest {
+        req.cookie(Cookie::new("access_token", "jwt"))
+            .cookie(Cookie::new(CSRF_COOKIE, "csrf-token"))
+    }
+
+    #[actix_rt::test]
+    async fn cookie_requests_have_to_echo_the_token() {
+        for uri in &["/scope", "/marked"] {
+            let post = || test::TestRequest::post().uri(uri);
+            let echoed = with_session(post()).header(CSRF_HEADER, "csrf-token");
+            assert_eq!(status(echoed).await, StatusCode::OK);
+            assert_eq!(status(with_session(post())).await, StatusCode::FORBIDDEN);
+            let wrong = with_session(post()).header(CSRF_HEADER, "csrf-tokem");
+            assert_eq!(status(wrong).await, StatusCode::FORBIDDEN);
+            let no_cookie = post()
+                .cookie(Cookie::new("refresh_token", "token"))
+                .header(CSRF_HEADER, "");
+            assert_eq!(status(no_cookie).await, StatusCode::FORBIDDEN);
+        }
+    }
+
+    #[actix_rt::test]
+    async fn requests_that_cant_be_forged_arent_checked() {
+        // Safe methods, requests without auth cookies and bearer tokens
+        let get = with_session(test::TestRequest::get().uri("/scope"));
+        assert_eq!(status(get).await, StatusCode::OK);
+        let anonymous = test::TestRequest::post().uri("/scope");
+        assert_eq!(status(anonymous).await, StatusCode::OK);
+        let bearer = with_session(test::TestRequest::post().uri("/scope"))
+            .header(header::AUTHORIZATION, "Bearer tapi_token");
+        assert_eq!(status(bearer).await, StatusCode::OK);
+        // Routes that aren't wrapped
+        let open = with_session(test::TestRequest::post().uri("/open"));
+        assert_eq!(status(open).await, StatusCode::OK);
+    }
+}
@@ -0,0 +1,304 @@
+use super::user::{User, UserClaims};
+use super::{db_error, now};
+use crate::errors::{auth, Error, ErrorCategories};
+use rand::prelude::*;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use sqlx::{Done, FromRow};
+
+pub type Pool = sqlx::PgPool;
+
+/// Prefix of every personal access token, so they can be told apart from access JWTs (and are easy
+/// to spot, e.g. by secret scanners)
+pub const TOKEN_PREFIX: &str = "tapi_";
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+/// What a personal access token is allowed to do, a write scope also grants the matching read
+/// scope
+pub enum Scope {
+    TodosRead,
+    TodosWrite,
+    CategoriesRead,
+    CategoriesWrite,
+}
+
+impl Scope {
+    /// Parse a scope from its string form (e.g. "todos:read")
+    pub fn parse(scope: &str) -> Option<Self> {
+        match scope {
+            "todos:read" => Some(Scope::TodosRead),
+            "todos:write" => Some(Scope::TodosWrite),
+            "categories:read" => Some(Scope::CategoriesRead),
+            "categories:write" => Some(Scope::CategoriesWrite),
+            _ => None,
+        }
+    }
+    /// Whether having `self` is enough for a route that requires `required`
+    pub fn grants(self, required: Scope) -> bool {
+        self == required
+            || matches!(
+                (self, required),
+                (Scope::TodosWrite, Scope::TodosRead)
+                    | (Scope::CategoriesWrite, Scope::CategoriesRead)
+            )
+    }
+}
+
+#[derive(Deserialize)]
+/// Personal access token creation request body
+pub struct AccessTokenInsert {
+    pub name: String,
+    pub scopes: Vec<String>,
+    /// Number of days until the token expires, it never expires if this is left out
+    pub expires_in_days: Option<i64>,
+}
+
+#[derive(Serialize, FromRow)]
+/// A personal access token as shown to its owner (the token itself is only stored hashed)
+pub struct AccessToken {
+    pub id: uuid::Uuid,
+    pub name: String,
+    pub scopes: Vec<String>,
+    pub expires_at: Option<i64>,
+    pub last_used_at: Option<i64>,
+    pub created_at: i64,
+}
+
+#[derive(Serialize)]
+/// Response body for a newly created personal access token, the only time the token is shown
+pub struct NewAccessToken {
+    pub token: String,
+    #[serde(flatten)]
+    pub info: AccessToken,
+}
+
+fn hash_token(token: &str) -> String {
+    format!("{:x}", Sha256::digest(token.as_bytes()))
+}
+
+impl AccessToken {
+    /// Takes in a (validated) token creation request body and creates a token for the user with
+    /// an id of `user_id`
+    pub async fn insert(
+        pool: &Pool,
+        user_id: uuid::Uuid,
+        token: AccessTokenInsert,
+    ) -> Result<NewAccessToken, Error> {
+        let mut bytes: [u8; 32] = [0; 32];
+        rand::thread_rng().fill_bytes(&mut bytes);
+        let secret = format!(
+            "{}{}",
+            TOKEN_PREFIX,
+            base64::encode_config(bytes, base64::URL_SAFE_NO_PAD)
+        );
+        let created_at = now();
+        let expires_at = token.expires_in_days.map(|d| created_at + d * 24 * 60 * 60);
+        let info = sqlx::query_as!(
+            AccessToken,
+            "INSERT INTO access_tokens (user_id, name, token_hash, scopes, expires_at, created_at)
+            VALUES ($1, $2, $3, $4, $5, $6)
+            RETURNING id, name, scopes, expires_at, last_used_at, created_at",
+            user_id,
+            token.name,
+            hash_token(&secret),
+            &token.scopes,
+            expires_at,
+            created_at,
+        )
+        .fetch_one(pool)
+        .await
+        .map_err(db_error)?;
+        Ok(NewAccessToken {
+            token: secret,
+            info,
+        })
+    }
+    /// Get all of a user's tokens that haven't been revoked
+    pub async fn get_all(pool: &Pool, user_id: uuid::Uuid) -> Result<Vec<Self>, Error> {
+        sqlx::query_as!(
+            AccessToken,
+            "SELECT id, name, scopes, expires_at, last_used_at, created_at FROM access_tokens
+            WHERE user_id=$1 AND revoked_at IS NULL ORDER BY created_at DESC",
+            user_id
+        )
+        .fetch_all(pool)
+        .await
+        .map_err(db_error)
+    }
+    /// Revoke one of a user's tokens, returning an `Error` if they have no such (unrevoked) token
+    pub async fn revoke(pool: &Pool, user_id: uuid::Uuid, id: uuid::Uuid) -> Result<(), Error> {
+        let done = sqlx::query!(
+            "UPDATE access_tokens SET revoked_at=$1
+            WHERE id=$2 AND user_id=$3 AND revoked_at IS NULL",
+            now(),
+            id,
+            user_id
+        )
+        .execute(pool)
+        .await
+        .map_err(db_error)?;
+        if done.rows_affected() == 0 {
+            return Err(auth::ErrorVariants::AccessTokenNotFound.to_error());
+        }
+        Ok(())
+    }
+    /// Revoke every one of a user's tokens (e.g. when their password has been reset by an admin)
+    pub async fn revoke_all(pool: &Pool, user_id: uuid::Uuid) -> Result<(), Error> {
+        sqlx::query!(
+            "UPDATE access_tokens SET revoked_at=$1 WHERE user_id=$2 AND revoked_at IS NULL",
+            now(),
+            user_id
+        )
+        .execute(pool)
+        .await
+        .map(|_| ())
+        .map_err(db_error)
+    }
+    /// Checks a token from an "Authorization: Bearer" header, returning claims for its owner
+    /// (limited to the token's scopes) and recording when it was last used
+    pub async fn authenticate(pool: &Pool, token: &str) -> Result<UserClaims, Error> {
+        if !token.starts_with(TOKEN_PREFIX) {
+            return Err(auth::ErrorVariants::InvalidAccessToken.to_error());
+        }
+        let row = sqlx::query!(
+            "SELECT id, user_id, scopes, expires_at FROM access_tokens
+            WHERE token_hash=$1 AND revoked_at IS NULL",
+            hash_token(token)
+        )
+        .fetch_optional(pool)
+        .await
+        .map_err(db_error)?
+        .ok_or_else(|| auth::ErrorVariants::InvalidAccessToken.to_error())?;
+        let now = now();
+        if matches!(row.expires_at, Some(exp) if exp <= now) {
+            return Err(auth::ErrorVariants::AccessTokenExpired.to_error());
+        }
+        sqlx::query!(
+            "UPDATE access_tokens SET last_used_at=$1 WHERE id=$2",
+            now,
+            row.id
+        )
+        .execute(pool)
+        .await
+        .map_err(db_error)?;
+        let user = User::get_by_id(pool, row.user_id)
+            .await
+            .map_err(|e| match e.error.body {
+                ErrorCategories::ResourceError(_) => {
+                    auth::ErrorVariants::InvalidAccessToken.to_error()
+                }
+                _ => e,
+            })?;
+        let mut claims = UserClaims::from_user(user);
+        claims.scopes = Some(row.scopes.iter().filter_map(|s| Scope::parse(s)).collect());
+        Ok(claims)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn variant(res: Result<(), Error>) -> Option<&'static str> {
+        res.err().map(|e| e.variant())
+    }
+
+    #[test]
+    fn write_scopes_grant_the_matching_read_scope() {
+        assert_eq!(Scope::parse("todos:write"), Some(Scope::TodosWrite));
+        assert_eq!(Scope::parse("todos:delete"), None);
+        assert!(Scope::TodosWrite.grants(Scope::TodosRead));
+        assert!(Scope::CategoriesWrite.grants(Scope::CategoriesRead));
+        assert!(!Scope::TodosRead.grants(Scope::TodosWrite));
+        assert!(!Scope::TodosWrite.grants(Scope::CategoriesRead));
+    }
+
+    #[actix_rt::test]
+    async fn tokens_are_limited_to_their_scopes() {
+        let pool = super::super::test_db_pool().await;
+        let email = format!("{}@example.com", uuid::Uuid::new_v4());
+        let user = User::insert_external(&pool, None, "pat-tester", &email, true)
+            .await
+            .unwrap();
+        let token = AccessToken::insert(
+            &pool,
+            user.id,
+            AccessTokenInsert {
+                name: "script".to_string(),
+                scopes: vec!["todos:read".to_string(), "categories:write".to_string()],
+                expires_in_days: None,
+            },
+        )
+        .await
+        .unwrap();
+        assert!(token.token.starts_with(TOKEN_PREFIX));
+
+        let claims = AccessToken::authenticate(&pool, &token.token)
+            .await
+            .unwrap();
+        assert_eq!(claims.id, user.id);
+        assert!(claims.require_scope(Scope::TodosRead).is_ok());
+        assert_eq!(
+            variant(claims.require_scope(Scope::TodosWrite)),
+            Some("InsufficientScope")
+        );
+        assert!(claims.require_scope(Scope::CategoriesRead).is_ok());
+        assert!(claims.require_scope(Scope::CategoriesWrite).is_ok());
+        // Tokens can't manage the account itself
+        assert_eq!(variant(claims.require_session()), Some("InsufficientScope"));
+        assert_eq!(variant(claims.require_admin()), Some("InsufficientScope"));
+
+        // Sessions aren't limited by scope
+        let session = UserClaims::from_user(User::get_by_id(&pool, user.id).await.unwrap());
+        assert!(session.require_scope(Scope::TodosWrite).is_ok());
+        assert!(session.require_session().is_ok());
+        assert_eq!(variant(session.require_admin()), Some("AdminRequired"));
+
+        AccessToken::revoke(&pool, user.id, token.info.id)
+            .await
+            .unwrap();
+        assert_eq!(
+            variant(
+                AccessToken::authenticate(&pool, &token.token)
+                    .await
+                    .map(|_| ())
+            ),
+            Some("InvalidAccessToken")
+        );
+        User::delete(&pool, user.id).await.unwrap();
+    }
+
+    #[actix_rt::test]
+    async fn expired_and_unknown_tokens_are_rejected() {
+        let pool = super::super::test_db_pool().await;
+        let email = format!("{}@example.com", uuid::Uuid::new_v4());
+        let user = User::insert_external(&pool, None, "pat-tester", &email, true)
+            .await
+            .unwrap();
+        let expired = AccessToken::insert(
+            &pool,
+            user.id,
+            AccessTokenInsert {
+                name: "expired".to_string(),
+                scopes: vec!["todos:read".to_string()],
+                expires_in_days: Some(0),
+            },
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            variant(
+                AccessToken::authenticate(&pool, &expired.token)
+                    .await
+                    .map(|_| ())
+            ),
+            Some("AccessTokenExpired")
+        );
+        let unknown = format!("{}unknown", TOKEN_PREFIX);
+        assert_eq!(
+            variant(AccessToken::authenticate(&pool, &unknown).await.map(|_| ())),
+            Some("InvalidAccessToken")
+        );
+        User::delete(&pool, user.id).await.unwrap();
+    }
+}
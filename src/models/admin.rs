@@ -0,0 +1,117 @@
+use super::db_error;
+use super::user::UserSafe;
+use crate::errors::{resource, Error};
+use serde::{Deserialize, Serialize};
+
+pub type Pool = sqlx::PgPool;
+
+/// Default (and maximum) number of users returned by a search
+const DEFAULT_LIMIT: i64 = 20;
+const MAX_LIMIT: i64 = 100;
+
+#[derive(Deserialize)]
+/// Query parameters for listing/searching users, `query` is matched against usernames, display
+/// names and emails
+pub struct UserSearch {
+    pub query: Option<String>,
+    pub limit: Option<i64>,
+    pub offset: Option<i64>,
+}
+
+#[derive(Serialize)]
+/// A user as seen by an admin (`UserSafe` plus the account's role and status)
+pub struct AdminUser {
+    #[serde(flatten)]
+    pub user: UserSafe,
+    pub role: String,
+    pub disabled: bool,
+}
+
+#[derive(Serialize)]
+/// A page of users, along with how many users matched in total
+pub struct AdminUserList {
+    pub users: Vec<AdminUser>,
+    pub total: i64,
+    pub limit: i64,
+    pub offset: i64,
+}
+
+#[derive(Serialize)]
+/// A single user as seen by an admin, with how many active sessions they have
+pub struct AdminUserDetails {
+    #[serde(flatten)]
+    pub user: AdminUser,
+    pub session_count: usize,
+}
+
+impl AdminUser {
+    /// Get a page of the users matching `search` (every user if there's no query), oldest first
+    pub async fn search(pool: &Pool, search: UserSearch) -> Result<AdminUserList, Error> {
+        let query = search
+            .query
+            .map(|q| q.trim().to_lowercase())
+            .filter(|q| !q.is_empty());
+        let limit = search.limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT);
+        let offset = search.offset.unwrap_or(0).max(0);
+        // `STRPOS` rather than `LIKE` so that "%" and "_" in the query aren't wildcards
+        let rows = sqlx::query!(
+            "SELECT id, displayname, username, email, email_verified, role, disabled, created_at,
+            COUNT(*) OVER() AS \"total!\" FROM users
+            WHERE $1::TEXT IS NULL OR STRPOS(LOWER(username), $1) > 0
+            OR STRPOS(LOWER(displayname), $1) > 0 OR STRPOS(LOWER(email), $1) > 0
+            ORDER BY created_at, id LIMIT $2 OFFSET $3",
+            query,
+            limit,
+            offset,
+        )
+        .fetch_all(pool)
+        .await
+        .map_err(db_error)?;
+        let total = rows.first().map_or(0, |r| r.total);
+        let users = rows
+            .into_iter()
+            .map(|row| AdminUser {
+                user: UserSafe {
+                    id: row.id,
+                    displayname: row.displayname,
+                    username: row.username,
+                    email: row.email,
+                    email_verified: row.email_verified,
+                    created_at: row.created_at,
+                },
+                role: row.role,
+                disabled: row.disabled,
+            })
+            .collect();
+        Ok(AdminUserList {
+            users,
+            total,
+            limit,
+            offset,
+        })
+    }
+    /// Get the user with an id of `id` or return `Error`
+    pub async fn get(pool: &Pool, id: uuid::Uuid) -> Result<Self, Error> {
+        let row = sqlx::query!(
+            "SELECT id, displayname, username, email, email_verified, role, disabled, created_at
+            FROM users WHERE id=$1",
+            id
+        )
+        .fetch_optional(pool)
+        .await
+        .map_err(db_error)?
+        .ok_or_else(|| resource::ErrorVariants::UserNotFound.to_error())?;
+        Ok(AdminUser {
+            user: UserSafe {
+                id: row.id,
+                displayname: row.displayname,
+                username: row.username,
+                email: row.email,
+                email_verified: row.email_verified,
+                created_at: row.created_at,
+            },
+            role: row.role,
+            disabled: row.disabled,
+        })
+    }
+}
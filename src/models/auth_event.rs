@@ -0,0 +1,127 @@
+use super::{db_error, now};
+use crate::errors::Error;
+use serde::{Deserialize, Serialize};
+use sqlx::FromRow;
+
+pub type Pool = sqlx::PgPool;
+
+/// Default (and maximum) number of events returned at once
+const DEFAULT_LIMIT: i64 = 50;
+const MAX_LIMIT: i64 = 200;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+/// Something security relevant that happened to an account
+pub enum AuthEventKind {
+    Register,
+    Login,
+    LoginFailed,
+    Refresh,
+    RefreshTokenReused,
+    SessionRevoked,
+    PasswordChanged,
+    PasswordChangeFailed,
+    AccountDisabled,
+    AccountEnabled,
+    ForcedLogout,
+    PasswordResetForced,
+}
+
+impl AuthEventKind {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            AuthEventKind::Register => "register",
+            AuthEventKind::Login => "login",
+            AuthEventKind::LoginFailed => "login_failed",
+            AuthEventKind::Refresh => "refresh",
+            AuthEventKind::RefreshTokenReused => "refresh_token_reused",
+            AuthEventKind::SessionRevoked => "session_revoked",
+            AuthEventKind::PasswordChanged => "password_changed",
+            AuthEventKind::PasswordChangeFailed => "password_change_failed",
+            AuthEventKind::AccountDisabled => "account_disabled",
+            AuthEventKind::AccountEnabled => "account_enabled",
+            AuthEventKind::ForcedLogout => "forced_logout",
+            AuthEventKind::PasswordResetForced => "password_reset_forced",
+        }
+    }
+}
+
+#[derive(Serialize, FromRow)]
+/// A row of the "auth_events" table
+pub struct AuthEvent {
+    pub id: uuid::Uuid,
+    /// `None` for failed logins with a username/email that doesn't belong to anyone
+    pub user_id: Option<uuid::Uuid>,
+    pub kind: String,
+    /// The username/email a failed login was attempted with
+    pub identifier: Option<String>,
+    pub ip: Option<String>,
+    pub os: String,
+    pub browser: String,
+    /// The admin that caused the event, for actions admins take on other users' accounts
+    pub actor_id: Option<uuid::Uuid>,
+    pub created_at: i64,
+}
+
+/// Details about the client that caused an event
+pub struct AuthEventInsert {
+    pub user_id: Option<uuid::Uuid>,
+    pub kind: AuthEventKind,
+    pub identifier: Option<String>,
+    pub ip: Option<String>,
+    pub os: String,
+    pub browser: String,
+    pub actor_id: Option<uuid::Uuid>,
+}
+
+#[derive(Deserialize)]
+/// Query parameters for listing events, every filter is optional (`user_id` is only used by
+/// admins)
+pub struct AuthEventQuery {
+    pub user_id: Option<uuid::Uuid>,
+    pub kind: Option<String>,
+    pub ip: Option<String>,
+    pub limit: Option<i64>,
+    pub offset: Option<i64>,
+}
+
+impl AuthEvent {
+    /// Record an event
+    pub async fn insert(pool: &Pool, event: AuthEventInsert) -> Result<(), Error> {
+        let created_at = now();
+        sqlx::query!(
+            "INSERT INTO auth_events (user_id, kind, identifier, ip, os, browser, actor_id,
+            created_at) VALUES ($1, $2, $3, $4, $5, $6, $7, $8)",
+            event.user_id,
+            event.kind.as_str(),
+            event.identifier,
+            event.ip,
+            event.os,
+            event.browser,
+            event.actor_id,
+            created_at,
+        )
+        .execute(pool)
+        .await
+        .map(|_| ())
+        .map_err(db_error)
+    }
+    /// Get the events matching `query`, newest first
+    pub async fn query(pool: &Pool, query: AuthEventQuery) -> Result<Vec<Self>, Error> {
+        sqlx::query_as!(
+            AuthEvent,
+            "SELECT id, user_id, kind, identifier, ip, os, browser, actor_id, created_at
+            FROM auth_events
+            WHERE ($1::UUID IS NULL OR user_id=$1) AND ($2::TEXT IS NULL OR kind=$2)
+            AND ($3::TEXT IS NULL OR ip=$3)
+            ORDER BY created_at DESC, id LIMIT $4 OFFSET $5",
+            query.user_id,
+            query.kind,
+            query.ip,
+            query.limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT),
+            query.offset.unwrap_or(0).max(0),
+        )
+        .fetch_all(pool)
+        .await
+        .map_err(db_error)
+    }
+}
@@ -1,19 +1,186 @@
+use super::todo::{Todo, TodoFilter};
+use super::{db_error, now};
+use crate::errors::{resource, Error};
 use serde::{Deserialize, Serialize};
-use sqlx::FromRow;
+use sqlx::{Done, FromRow};
 
-#[derive(Serialize, Deserialize)]
+pub type Pool = sqlx::PgPool;
+
+#[derive(Deserialize)]
+/// Category creation request body
 pub struct CategoryInsert {
-    pub user_id: uuid::Uuid,
     pub name: String,
     pub description: String,
 }
 
+#[derive(Deserialize)]
+/// Category update request body, fields that are left out aren't changed
+pub struct CategoryUpdate {
+    pub name: Option<String>,
+    pub description: Option<String>,
+}
+
+#[derive(Deserialize)]
+/// Query parameters for listing categories
+pub struct CategoryQuery {
+    pub limit: Option<i64>,
+}
+
 #[derive(Serialize, FromRow)]
+/// A row of the "categories" table
 pub struct Category {
     pub id: uuid::Uuid,
-    pub user_ud: uuid::Uuid,
+    pub user_id: uuid::Uuid,
     pub name: String,
     pub description: String,
-    pub created_at: u64,
-    pub updated_at: u64,
+    pub created_at: i64,
+    pub updated_at: i64,
+}
+
+#[derive(Serialize)]
+/// A category with (some of) its todos joined in
+pub struct CategoryWithTodos {
+    #[serde(flatten)]
+    pub category: Category,
+    pub todos: Vec<Todo>,
+}
+
+impl Category {
+    /// Takes in a (validated) category creation request body and creates the category for the
+    /// user with an id of `user_id`
+    pub async fn insert(
+        pool: &Pool,
+        user_id: uuid::Uuid,
+        ins: CategoryInsert,
+    ) -> Result<Self, Error> {
+        let now = now();
+        sqlx::query_as!(
+            Category,
+            "INSERT INTO categories (user_id, name, description, created_at, updated_at)
+            VALUES ($1, $2, $3, $4, $4)
+            RETURNING id, user_id, name, description, created_at, updated_at",
+            user_id,
+            ins.name,
+            ins.description,
+            now,
+        )
+        .fetch_one(pool)
+        .await
+        .map_err(db_error)
+    }
+    /// Get the user's categories (at most `limit` of them), oldest first
+    pub async fn get_all(
+        pool: &Pool,
+        user_id: uuid::Uuid,
+        limit: Option<i64>,
+    ) -> Result<Vec<Self>, Error> {
+        sqlx::query_as!(
+            Category,
+            "SELECT id, user_id, name, description, created_at, updated_at FROM categories
+            WHERE user_id=$1 ORDER BY created_at, id LIMIT $2",
+            user_id,
+            limit.map(|l| l.max(0)),
+        )
+        .fetch_all(pool)
+        .await
+        .map_err(db_error)
+    }
+    /// Get the user's category with an id of `id` along with the todos in it that match `filter`
+    /// (at most `limit` of them, oldest first), all in one query
+    pub async fn get_with_todos(
+        pool: &Pool,
+        user_id: uuid::Uuid,
+        id: uuid::Uuid,
+        filter: TodoFilter,
+        limit: Option<i64>,
+    ) -> Result<CategoryWithTodos, Error> {
+        // One row per todo (or a single row of NULL todo columns if none match), each with the
+        // category's columns repeated
+        let rows = sqlx::query!(
+            "SELECT c.id, c.user_id, c.name, c.description, c.created_at, c.updated_at,
+            t.id AS \"todo_id?\", t.title AS \"todo_title?\",
+            t.description AS \"todo_description?\", t.completed AS \"todo_completed?\",
+            t.created_at AS \"todo_created_at?\", t.updated_at AS \"todo_updated_at?\"
+            FROM categories c LEFT JOIN LATERAL (
+                SELECT id, title, description, completed, created_at, updated_at FROM todos
+                WHERE cat_id=c.id AND ($3::BOOLEAN IS NULL OR completed=$3)
+                ORDER BY created_at, id LIMIT $4
+            ) t ON TRUE
+            WHERE c.id=$1 AND c.user_id=$2
+            ORDER BY t.created_at, t.id",
+            id,
+            user_id,
+            filter.completed(),
+            limit.map(|l| l.max(0)),
+        )
+        .fetch_all(pool)
+        .await
+        .map_err(db_error)?;
+        let first = rows
+            .first()
+            .ok_or_else(|| resource::ErrorVariants::CategoryNotFound.to_error())?;
+        let category = Category {
+            id: first.id,
+            user_id: first.user_id,
+            name: first.name.clone(),
+            description: first.description.clone(),
+            created_at: first.created_at,
+            updated_at: first.updated_at,
+        };
+        let todos = rows
+            .into_iter()
+            .filter_map(|row| {
+                Some(Todo {
+                    id: row.todo_id?,
+                    user_id: row.user_id,
+                    cat_id: row.id,
+                    title: row.todo_title?,
+                    description: row.todo_description?,
+                    completed: row.todo_completed?,
+                    created_at: row.todo_created_at?,
+                    updated_at: row.todo_updated_at?,
+                })
+            })
+            .collect();
+        Ok(CategoryWithTodos { category, todos })
+    }
+    /// Takes in a (validated) category update request body and applies it to the user's category
+    /// with an id of `id`, returning the updated category
+    pub async fn update(
+        pool: &Pool,
+        user_id: uuid::Uuid,
+        id: uuid::Uuid,
+        upd: CategoryUpdate,
+    ) -> Result<Self, Error> {
+        sqlx::query_as!(
+            Category,
+            "UPDATE categories SET name=COALESCE($1, name),
+            description=COALESCE($2, description) WHERE id=$3 AND user_id=$4
+            RETURNING id, user_id, name, description, created_at, updated_at",
+            upd.name,
+            upd.description,
+            id,
+            user_id,
+        )
+        .fetch_optional(pool)
+        .await
+        .map_err(db_error)?
+        .ok_or_else(|| resource::ErrorVariants::CategoryNotFound.to_error())
+    }
+    /// Delete the user's category with an id of `id` along with its todos (removed by
+    /// `ON DELETE CASCADE`)
+    pub async fn delete(pool: &Pool, user_id: uuid::Uuid, id: uuid::Uuid) -> Result<(), Error> {
+        let done = sqlx::query!(
+            "DELETE FROM categories WHERE id=$1 AND user_id=$2",
+            id,
+            user_id
+        )
+        .execute(pool)
+        .await
+        .map_err(db_error)?;
+        if done.rows_affected() == 0 {
+            return Err(resource::ErrorVariants::CategoryNotFound.to_error());
+        }
+        Ok(())
+    }
 }
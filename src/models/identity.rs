@@ -0,0 +1,90 @@
+use super::{db_error, now};
+use crate::errors::{auth, Error};
+use serde::Serialize;
+use sqlx::{Done, FromRow};
+
+pub type Pool = sqlx::PgPool;
+
+#[derive(Serialize, FromRow)]
+/// An external (OpenID Connect) identity linked to a user
+pub struct UserIdentity {
+    pub id: uuid::Uuid,
+    pub provider: String,
+    pub email: Option<String>,
+    pub created_at: i64,
+}
+
+impl UserIdentity {
+    /// Get the id of the user that the identity `subject` from `provider` is linked to
+    pub async fn get_user_id(
+        pool: &Pool,
+        provider: &str,
+        subject: &str,
+    ) -> Result<Option<uuid::Uuid>, Error> {
+        sqlx::query!(
+            "SELECT user_id FROM user_identities WHERE provider=$1 AND subject=$2",
+            provider,
+            subject
+        )
+        .fetch_optional(pool)
+        .await
+        .map(|row| row.map(|r| r.user_id))
+        .map_err(db_error)
+    }
+    /// Get all the identities linked to a user
+    pub async fn get_all(pool: &Pool, user_id: uuid::Uuid) -> Result<Vec<Self>, Error> {
+        sqlx::query_as!(
+            UserIdentity,
+            "SELECT id, provider, email, created_at FROM user_identities
+            WHERE user_id=$1 ORDER BY created_at",
+            user_id
+        )
+        .fetch_all(pool)
+        .await
+        .map_err(db_error)
+    }
+    /// Link the identity `subject` from `provider` to a user, returning an `Error` if it's linked
+    /// to someone else (or the user already has a different identity from `provider`)
+    pub async fn link(
+        pool: &Pool,
+        user_id: uuid::Uuid,
+        provider: &str,
+        subject: &str,
+        email: Option<&str>,
+    ) -> Result<(), Error> {
+        let created_at = now();
+        let done = sqlx::query!(
+            "INSERT INTO user_identities (user_id, provider, subject, email, created_at)
+            VALUES ($1, $2, $3, $4, $5) ON CONFLICT DO NOTHING",
+            user_id,
+            provider,
+            subject,
+            email,
+            created_at
+        )
+        .execute(pool)
+        .await
+        .map_err(db_error)?;
+        if done.rows_affected() == 0
+            && Self::get_user_id(pool, provider, subject).await? != Some(user_id)
+        {
+            return Err(auth::ErrorVariants::IdentityAlreadyLinked.to_error());
+        }
+        Ok(())
+    }
+    /// Unlink a user's identity from `provider`
+    pub async fn unlink(pool: &Pool, user_id: uuid::Uuid, provider: &str) -> Result<(), Error> {
+        let done = sqlx::query!(
+            "DELETE FROM user_identities WHERE user_id=$1 AND provider=$2",
+            user_id,
+            provider
+        )
+        .execute(pool)
+        .await
+        .map_err(db_error)?;
+        if done.rows_affected() == 0 {
+            return Err(auth::ErrorVariants::IdentityNotFound.to_error());
+        }
+        Ok(())
+    }
+}
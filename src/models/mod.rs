@@ -1,3 +1,53 @@
+pub mod access_token;
+pub mod admin;
+pub mod auth_event;
 pub mod category;
+pub mod identity;
+pub mod oidc;
+pub mod password;
+pub mod password_reset;
+pub mod session;
+pub mod signing;
+pub mod throttle;
 pub mod todo;
+pub mod totp;
 pub mod user;
+pub mod verification;
+
+use crate::errors::{internal_server, Error};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Log a database error and turn it into a DBError, the actual error is only necessary to us and
+/// not the client
+pub fn db_error(e: sqlx::Error) -> Error {
+    eprintln!("Database Error: {}", e);
+    internal_server::ErrorVariants::DBError.to_error()
+}
+
+/// The current time as a unix timestamp (in seconds), which is how the tables store times
+pub fn now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs() as i64
+}
+
+#[cfg(test)]
+/// Connect to the redis server in `REDIS_CONN`, for the tests that need one (they're ignored by
+/// default, run them with `cargo test -- --ignored`)
+pub fn test_redis_pool() -> session::RedisPool {
+    dotenv::dotenv().ok();
+    let redis_conn = std::env::var("REDIS_CONN").expect("REDIS_CONN env var unset");
+    r2d2::Pool::builder()
+        .build(redis::Client::open(redis_conn).unwrap())
+        .unwrap()
+}
+
+#[cfg(test)]
+/// Connect to the database in `DB_CONN`, for the tests that need one (the schema has to have been
+/// applied already)
+pub async fn test_db_pool() -> sqlx::PgPool {
+    dotenv::dotenv().ok();
+    let db_conn = std::env::var("DB_CONN").expect("DB_CONN env var unset");
+    sqlx::PgPool::connect(&db_conn).await.unwrap()
+}
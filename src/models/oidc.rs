@@ -0,0 +1,524 @@
+use super::session::{store_error, RedisPool};
+use crate::errors::{auth, internal_server, Error};
+use lazy_static::lazy_static;
+use rand::prelude::*;
+use redis::Commands;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{collections::HashMap, env};
+
+/// Life of a sign in request (from redirecting to the provider until the callback) in seconds
+pub const STATE_LIFE: usize = 10 * 60;
+/// Largest discovery/JWKS/token response that will be read from a provider
+const RESPONSE_LIMIT: usize = 1024 * 1024;
+
+lazy_static! {
+    /// External OpenID Connect providers, configured with a comma separated list of names in
+    /// `OIDC_PROVIDERS` and then `OIDC_<NAME>_ISSUER`, `OIDC_<NAME>_CLIENT_ID`,
+    /// `OIDC_<NAME>_CLIENT_SECRET` (optional, PKCE is always used), `OIDC_<NAME>_REDIRECT_URL`
+    /// (optional) and `OIDC_<NAME>_SCOPES` (optional) for each one
+    static ref PROVIDERS: HashMap<String, OidcProvider> = env::var("OIDC_PROVIDERS")
+        .unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .filter(|name| !name.is_empty())
+        .map(|name| (name.to_string(), OidcProvider::from_env(name)))
+        .collect();
+}
+
+/// An external OpenID Connect provider that users can sign in with
+pub struct OidcProvider {
+    pub name: String,
+    issuer: String,
+    client_id: String,
+    client_secret: Option<String>,
+    redirect_url: String,
+    scopes: String,
+}
+
+#[derive(Deserialize)]
+/// The parts of a provider's discovery document that are needed
+struct ProviderMetadata {
+    issuer: String,
+    authorization_endpoint: String,
+    token_endpoint: String,
+    jwks_uri: String,
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    id_token: String,
+}
+
+#[derive(Deserialize)]
+struct JwkSet {
+    keys: Vec<Jwk>,
+}
+
+#[derive(Deserialize)]
+struct Jwk {
+    kty: String,
+    kid: Option<String>,
+    n: Option<String>,
+    e: Option<String>,
+}
+
+#[derive(Deserialize)]
+/// The claims of a verified ID token
+pub struct IdTokenClaims {
+    pub sub: String,
+    pub email: Option<String>,
+    pub email_verified: Option<bool>,
+    pub name: Option<String>,
+    pub preferred_username: Option<String>,
+    nonce: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+/// A sign in request that is waiting for the provider to redirect back, stored in redis under the
+/// random "state" parameter
+pub struct OidcState {
+    pub provider: String,
+    verifier: String,
+    nonce: String,
+    /// Set when an already logged in user is linking the provider to their account
+    pub link_user: Option<uuid::Uuid>,
+    /// Hash of the secret given to the browser that started the sign in, so the callback can't be
+    /// completed in another browser (which would log it into, or link, someone else's account)
+    browser_hash: String,
+}
+
+#[derive(Deserialize)]
+/// Query parameters the provider redirects back with
+pub struct OidcCallback {
+    pub state: String,
+    pub code: Option<String>,
+    pub error: Option<String>,
+}
+
+fn provider_error<E: std::fmt::Display>(e: E) -> Error {
+    eprintln!("OIDC Provider Error: {}", e);
+    internal_server::ErrorVariants::OidcProviderError.to_error()
+}
+
+/// Random URL safe string made from `len` random bytes
+fn random_string(len: usize) -> String {
+    let mut bytes = vec![0; len];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    base64::encode_config(bytes, base64::URL_SAFE_NO_PAD)
+}
+
+/// Hash of the secret that binds a sign in request to a browser, only the hash is stored
+fn hash_browser_secret(secret: &str) -> String {
+    base64::encode_config(Sha256::digest(secret.as_bytes()), base64::URL_SAFE_NO_PAD)
+}
+
+async fn get_json<T: DeserializeOwned>(url: &str) -> Result<T, Error> {
+    let mut res = awc::Client::default()
+        .get(url)
+        .send()
+        .await
+        .map_err(provider_error)?;
+    if !res.status().is_success() {
+        return Err(provider_error(format!(
+            "GET {} returned {}",
+            url,
+            res.status()
+        )));
+    }
+    res.json::<T>()
+        .limit(RESPONSE_LIMIT)
+        .await
+        .map_err(provider_error)
+}
+
+impl OidcProvider {
+    fn from_env(name: &str) -> Self {
+        let var = |key: &str| env::var(format!("OIDC_{}_{}", name.to_uppercase(), key));
+        OidcProvider {
+            name: name.to_string(),
+            issuer: var("ISSUER")
+                .unwrap_or_else(|_| panic!("OIDC_{}_ISSUER env var unset", name.to_uppercase()))
+                .trim_end_matches('/')
+                .to_string(),
+            client_id: var("CLIENT_ID")
+                .unwrap_or_else(|_| panic!("OIDC_{}_CLIENT_ID env var unset", name.to_uppercase())),
+            client_secret: var("CLIENT_SECRET").ok(),
+            redirect_url: var("REDIRECT_URL").unwrap_or_else(|_| {
+                format!(
+                    "{}/api/users/auth/oidc/{}/callback",
+                    env::var("PUBLIC_URL")
+                        .expect("PUBLIC_URL env var unset")
+                        .trim_end_matches('/'),
+                    name
+                )
+            }),
+            scopes: var("SCOPES").unwrap_or_else(|_| "openid email profile".to_string()),
+        }
+    }
+    /// Get a configured provider by name
+    pub fn get(name: &str) -> Result<&'static Self, Error> {
+        PROVIDERS
+            .get(name)
+            .ok_or_else(|| auth::ErrorVariants::OidcProviderNotFound.to_error())
+    }
+    /// Fetch the provider's discovery document (the issuer in it has to match the configured one)
+    async fn metadata(&self) -> Result<ProviderMetadata, Error> {
+        let metadata: ProviderMetadata =
+            get_json(&format!("{}/.well-known/openid-configuration", self.issuer)).await?;
+        if metadata.issuer.trim_end_matches('/') != self.issuer {
+            return Err(provider_error(format!(
+                "issuer mismatch, expected {} got {}",
+                self.issuer, metadata.issuer
+            )));
+        }
+        Ok(metadata)
+    }
+    /// Start a sign in, storing the PKCE verifier + nonce and returning the URL of the provider's
+    /// authorization page to redirect the user to along with a secret for the browser to hand back
+    /// to the callback
+    pub async fn authorization_url(
+        &self,
+        conn: &RedisPool,
+        link_user: Option<uuid::Uuid>,
+    ) -> Result<(String, String), Error> {
+        let metadata = self.metadata().await?;
+        let state = random_string(32);
+        let browser_secret = random_string(32);
+        let oidc_state = OidcState {
+            provider: self.name.clone(),
+            verifier: random_string(32),
+            nonce: random_string(16),
+            link_user,
+            browser_hash: hash_browser_secret(&browser_secret),
+        };
+        let challenge = base64::encode_config(
+            Sha256::digest(oidc_state.verifier.as_bytes()),
+            base64::URL_SAFE_NO_PAD,
+        );
+        let mut url = url::Url::parse(&metadata.authorization_endpoint).map_err(provider_error)?;
+        url.query_pairs_mut()
+            .append_pair("response_type", "code")
+            .append_pair("client_id", &self.client_id)
+            .append_pair("redirect_uri", &self.redirect_url)
+            .append_pair("scope", &self.scopes)
+            .append_pair("state", &state)
+            .append_pair("nonce", &oidc_state.nonce)
+            .append_pair("code_challenge", &challenge)
+            .append_pair("code_challenge_method", "S256");
+        let mut conn = conn.get().map_err(store_error)?;
+        conn.set_ex::<_, _, ()>(
+            format!("oidc_state:{}", state),
+            serde_json::to_string(&oidc_state).unwrap(),
+            STATE_LIFE,
+        )
+        .map_err(store_error)?;
+        Ok((url.to_string(), browser_secret))
+    }
+    /// Swap an authorization code for an ID token and verify it (signature, issuer, audience,
+    /// expiry and nonce), returning its claims
+    pub async fn exchange_code(
+        &self,
+        code: &str,
+        state: &OidcState,
+    ) -> Result<IdTokenClaims, Error> {
+        let metadata = self.metadata().await?;
+        let mut form = vec![
+            ("grant_type", "authorization_code"),
+            ("code", code),
+            ("redirect_uri", &self.redirect_url),
+            ("client_id", &self.client_id),
+            ("code_verifier", &state.verifier),
+        ];
+        if let Some(secret) = &self.client_secret {
+            form.push(("client_secret", secret));
+        }
+        let mut res = awc::Client::default()
+            .post(&metadata.token_endpoint)
+            .send_form(&form)
+            .await
+            .map_err(provider_error)?;
+        if res.status().is_client_error() {
+            // Most likely an invalid/expired/reused code
+            eprintln!("OIDC token request rejected with {}", res.status());
+            return Err(auth::ErrorVariants::OidcLoginFailed.to_error());
+        } else if !res.status().is_success() {
+            return Err(provider_error(format!(
+                "token request returned {}",
+                res.status()
+            )));
+        }
+        let token: TokenResponse = res
+            .json()
+            .limit(RESPONSE_LIMIT)
+            .await
+            .map_err(provider_error)?;
+        self.verify_id_token(&metadata, &token.id_token, &state.nonce)
+            .await
+    }
+    async fn verify_id_token(
+        &self,
+        metadata: &ProviderMetadata,
+        id_token: &str,
+        nonce: &str,
+    ) -> Result<IdTokenClaims, Error> {
+        let login_failed = |e: &dyn std::fmt::Display| {
+            eprintln!("Invalid ID token: {}", e);
+            auth::ErrorVariants::OidcLoginFailed.to_error()
+        };
+        let header = jsonwebtoken::decode_header(id_token).map_err(|e| login_failed(&e))?;
+        if header.alg != jsonwebtoken::Algorithm::RS256 {
+            return Err(login_failed(&format!(
+                "unsupported algorithm {:?}",
+                header.alg
+            )));
+        }
+        // Find the key that signed the token (a token without a "kid" is only accepted if the
+        // provider has a single RSA key)
+        let jwks: JwkSet = get_json(&metadata.jwks_uri).await?;
+        let mut keys = jwks.keys.iter().filter(|k| k.kty == "RSA");
+        let key = match &header.kid {
+            Some(kid) => keys.find(|k| k.kid.as_ref() == Some(kid)),
+            None => match (keys.next(), keys.next()) {
+                (Some(k), None) => Some(k),
+                _ => None,
+            },
+        };
+        let (n, e) = match key {
+            Some(Jwk {
+                n: Some(n),
+                e: Some(e),
+                ..
+            }) => (n, e),
+            _ => return Err(login_failed(&"signing key not found")),
+        };
+        let key =
+            jsonwebtoken::DecodingKey::from_rsa_components(n, e).map_err(|e| login_failed(&e))?;
+        let mut validation = jsonwebtoken::Validation::new(jsonwebtoken::Algorithm::RS256);
+        validation.leeway = 60;
+        validation.set_issuer(&[&metadata.issuer]);
+        validation.set_audience(&[&self.client_id]);
+        let claims = jsonwebtoken::decode::<IdTokenClaims>(id_token, &key, &validation)
+            .map_err(|e| login_failed(&e))?
+            .claims;
+        if claims.nonce.as_deref() != Some(nonce) {
+            return Err(login_failed(&"nonce mismatch"));
+        }
+        Ok(claims)
+    }
+}
+
+impl OidcState {
+    /// Get and remove the sign in request for `state`, so each one can only be completed once,
+    /// returning an `Error` unless `browser_secret` is the secret given to the browser that started
+    /// it
+    pub fn take(conn: &RedisPool, state: &str, browser_secret: &str) -> Result<Self, Error> {
+        let key = format!("oidc_state:{}", state);
+        let mut conn = conn.get().map_err(store_error)?;
+        let (value,): (Option<String>,) = redis::pipe()
+            .atomic()
+            .get(&key)
+            .del(&key)
+            .ignore()
+            .query(&mut *conn)
+            .map_err(store_error)?;
+        value
+            .and_then(|v| serde_json::from_str::<Self>(&v).ok())
+            // Compare in constant time so the hash can't be guessed a byte at a time
+            .filter(|s| {
+                let hash = hash_browser_secret(browser_secret);
+                hash.len() == s.browser_hash.len()
+                    && openssl::memcmp::eq(hash.as_bytes(), s.browser_hash.as_bytes())
+            })
+            .ok_or_else(|| auth::ErrorVariants::InvalidOidcState.to_error())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{test, web, App, HttpRequest, HttpResponse};
+    use openssl::{pkey::PKey, rsa::Rsa};
+    use serde_json::json;
+
+    const CLIENT_ID: &str = "todoapi";
+
+    /// The mock issuer's RSA key, as a PEM for signing and a JWK for its JWKS
+    struct MockKey {
+        pem: Vec<u8>,
+        jwk: serde_json::Value,
+    }
+
+    fn issuer_url(req: &HttpRequest) -> String {
+        format!("http://{}", req.connection_info().host())
+    }
+
+    async fn discovery(req: HttpRequest) -> HttpResponse {
+        let issuer = issuer_url(&req);
+        HttpResponse::Ok().json(json!({
+            "issuer": issuer,
+            "authorization_endpoint": format!("{}/authorize", issuer),
+            "token_endpoint": format!("{}/token", issuer),
+            "jwks_uri": format!("{}/jwks", issuer),
+        }))
+    }
+
+    async fn jwks(key: web::Data<MockKey>) -> HttpResponse {
+        HttpResponse::Ok().json(json!({ "keys": [key.jwk] }))
+    }
+
+    /// Swaps any code for an ID token, the code stands in for the nonce the issuer would have been
+    /// given at its authorization endpoint ("rejected" is treated as an invalid code)
+    async fn token(
+        req: HttpRequest,
+        key: web::Data<MockKey>,
+        form: web::Form<HashMap<String, String>>,
+    ) -> HttpResponse {
+        let code = &form["code"];
+        if code == "rejected" || form["code_verifier"].is_empty() {
+            return HttpResponse::BadRequest().json(json!({ "error": "invalid_grant" }));
+        }
+        let claims = json!({
+            "iss": issuer_url(&req),
+            "aud": CLIENT_ID,
+            "sub": "mock-user",
+            "email": "mock@example.com",
+            "email_verified": true,
+            "name": "Mock User",
+            "preferred_username": "mock",
+            "nonce": code,
+            "exp": super::super::now() + 60,
+        });
+        let header = jsonwebtoken::Header {
+            kid: Some("mock-key".to_string()),
+            ..jsonwebtoken::Header::new(jsonwebtoken::Algorithm::RS256)
+        };
+        let encoding = jsonwebtoken::EncodingKey::from_rsa_pem(&key.pem).unwrap();
+        let id_token = jsonwebtoken::encode(&header, &claims, &encoding).unwrap();
+        HttpResponse::Ok().json(json!({ "id_token": id_token, "token_type": "Bearer" }))
+    }
+
+    /// Start a mock issuer and get a provider configured to use it
+    fn mock_issuer(client_id: &str) -> (test::TestServer, OidcProvider) {
+        let rsa = Rsa::generate(2048).unwrap();
+        let pem = PKey::from_rsa(rsa.clone())
+            .unwrap()
+            .private_key_to_pem_pkcs8()
+            .unwrap();
+        let encode = |n: Vec<u8>| base64::encode_config(n, base64::URL_SAFE_NO_PAD);
+        let jwk = json!({
+            "kty": "RSA",
+            "kid": "mock-key",
+            "n": encode(rsa.n().to_vec()),
+            "e": encode(rsa.e().to_vec()),
+        });
+        let srv = test::start(move || {
+            App::new()
+                .data(MockKey {
+                    pem: pem.clone(),
+                    jwk: jwk.clone(),
+                })
+                .route(
+                    "/.well-known/openid-configuration",
+                    web::get().to(discovery),
+                )
+                .route("/jwks", web::get().to(jwks))
+                .route("/token", web::post().to(token))
+        });
+        let provider = OidcProvider {
+            name: "mock".to_string(),
+            issuer: srv.url("").trim_end_matches('/').to_string(),
+            client_id: client_id.to_string(),
+            client_secret: None,
+            redirect_url: "http://localhost:5000/api/users/auth/oidc/mock/callback".to_string(),
+            scopes: "openid email profile".to_string(),
+        };
+        (srv, provider)
+    }
+
+    fn state(nonce: &str) -> OidcState {
+        OidcState {
+            provider: "mock".to_string(),
+            verifier: random_string(32),
+            nonce: nonce.to_string(),
+            link_user: None,
+            browser_hash: hash_browser_secret("secret"),
+        }
+    }
+
+    fn variant<T>(res: Result<T, Error>) -> Option<&'static str> {
+        res.err().map(|e| e.variant())
+    }
+
+    #[actix_rt::test]
+    async fn codes_are_exchanged_for_verified_claims() {
+        let (_srv, provider) = mock_issuer(CLIENT_ID);
+        let claims = provider
+            .exchange_code("nonce-1", &state("nonce-1"))
+            .await
+            .unwrap();
+        assert_eq!(claims.sub, "mock-user");
+        assert_eq!(claims.email.as_deref(), Some("mock@example.com"));
+        assert_eq!(claims.email_verified, Some(true));
+        assert_eq!(claims.name.as_deref(), Some("Mock User"));
+        assert_eq!(claims.preferred_username.as_deref(), Some("mock"));
+    }
+
+    #[actix_rt::test]
+    async fn invalid_codes_and_id_tokens_are_rejected() {
+        let (_srv, provider) = mock_issuer(CLIENT_ID);
+        let rejected = provider.exchange_code("rejected", &state("rejected")).await;
+        assert_eq!(variant(rejected), Some("OidcLoginFailed"));
+        // An ID token issued for another sign in request
+        let replayed = provider.exchange_code("nonce-1", &state("nonce-2")).await;
+        assert_eq!(variant(replayed), Some("OidcLoginFailed"));
+        // An ID token issued to another client
+        let (_srv, other_client) = mock_issuer("another-client");
+        let other = other_client
+            .exchange_code("nonce-1", &state("nonce-1"))
+            .await;
+        assert_eq!(variant(other), Some("OidcLoginFailed"));
+    }
+
+    #[actix_rt::test]
+    #[ignore = "needs a redis server at REDIS_CONN"]
+    async fn sign_in_requests_are_bound_to_the_browser() {
+        let pool = super::super::test_redis_pool();
+        let (_srv, provider) = mock_issuer(CLIENT_ID);
+        let (url, browser_secret) = provider.authorization_url(&pool, None).await.unwrap();
+        let url = url::Url::parse(&url).unwrap();
+        assert!(url
+            .as_str()
+            .starts_with(&format!("{}/authorize", provider.issuer)));
+        let query: HashMap<String, String> = url.query_pairs().into_owned().collect();
+        assert_eq!(query["client_id"], CLIENT_ID);
+        assert_eq!(query["code_challenge_method"], "S256");
+
+        // Another browser can't complete it, and it's gone after one try
+        let (other_url, _) = provider.authorization_url(&pool, None).await.unwrap();
+        let other_url = url::Url::parse(&other_url).unwrap();
+        let other_state = other_url
+            .query_pairs()
+            .find(|(key, _)| key == "state")
+            .unwrap()
+            .1
+            .into_owned();
+        let stolen = OidcState::take(&pool, &other_state, &browser_secret);
+        assert_eq!(variant(stolen), Some("InvalidOidcState"));
+
+        let oidc_state = OidcState::take(&pool, &query["state"], &browser_secret).unwrap();
+        assert_eq!(oidc_state.nonce, query["nonce"]);
+        let challenge = base64::encode_config(
+            Sha256::digest(oidc_state.verifier.as_bytes()),
+            base64::URL_SAFE_NO_PAD,
+        );
+        assert_eq!(query["code_challenge"], challenge);
+        let claims = provider
+            .exchange_code(&oidc_state.nonce, &oidc_state)
+            .await
+            .unwrap();
+        assert_eq!(claims.sub, "mock-user");
+        let again = OidcState::take(&pool, &query["state"], &browser_secret);
+        assert_eq!(variant(again), Some("InvalidOidcState"));
+    }
+}
@@ -0,0 +1,280 @@
+use crate::errors::{auth, internal_server, Error};
+use argon2::{Config, ThreadMode, Variant, Version};
+use lazy_static::lazy_static;
+use rand::prelude::*;
+use sha2::{Digest, Sha256};
+use std::env;
+
+lazy_static! {
+    /// Secret that used to be shared as the salt for every password, hashes still salted with it
+    /// are upgraded on login
+    static ref SECRET: String = env::var("SECRET").expect("SECRET env var unset");
+    /// Optional server-side pepper, mixed into each hash as the argon2 secret (so it is never
+    /// stored alongside the hash like the salt is)
+    static ref PEPPER: Option<String> = env_pepper("PASSWORD_PEPPER");
+    /// Optional pepper that was replaced by `PEPPER`, hashes still using it are accepted and
+    /// upgraded on login
+    static ref PREVIOUS_PEPPER: Option<String> = env_pepper("PASSWORD_PEPPER_PREVIOUS");
+    /// Argon2 variant used for new hashes
+    static ref VARIANT: Variant = env::var("ARGON2_VARIANT")
+        .map(|v| Variant::from_str(&v).expect("ARGON2_VARIANT must be argon2i, argon2d or argon2id"))
+        .unwrap_or(Variant::Argon2id);
+    /// Argon2 memory cost (in KiB) used for new hashes
+    static ref MEMORY: u32 = env_u32("ARGON2_MEMORY", 19456);
+    /// Argon2 number of iterations used for new hashes
+    static ref ITERATIONS: u32 = env_u32("ARGON2_ITERATIONS", 2);
+}
+
+/// Number of lanes (degree of parallelism) used for new hashes
+const LANES: u32 = 1;
+
+/// Prefix of hashes made with a pepper, followed by the `pepper_id` of that pepper so verifying
+/// only has to try the one pepper (`$pepper=<id>$<variant>$...`)
+const PEPPER_PREFIX: &str = "$pepper=";
+
+/// Parse a `u32` from the environment variable `key` or fall back to `default` if it is unset
+fn env_u32(key: &str, default: u32) -> u32 {
+    env::var(key)
+        .map(|v| {
+            v.parse()
+                .unwrap_or_else(|_| panic!("{} must be a positive integer", key))
+        })
+        .unwrap_or(default)
+}
+
+/// Read an optional pepper from the environment variable `key`, treating an empty value as unset
+fn env_pepper(key: &str) -> Option<String> {
+    env::var(key).ok().filter(|p| !p.is_empty())
+}
+
+/// A short id naming `pepper` in the hashes made with it, without storing the pepper itself
+fn pepper_id(pepper: &str) -> String {
+    let digest = Sha256::digest(format!("password-pepper:{}", pepper).as_bytes());
+    format!("{:x}", digest)[..16].to_string()
+}
+
+/// Split a stored hash into the id of the pepper it was made with (if it has one) and the
+/// encoded argon2 hash
+fn split_pepper(hash: &str) -> (Option<&str>, &str) {
+    if let Some(rest) = hash.strip_prefix(PEPPER_PREFIX) {
+        if let Some(end) = rest.find('$') {
+            return (Some(&rest[..end]), &rest[end..]);
+        }
+    }
+    (None, hash)
+}
+
+/// Whether an encoded hash is salted with the old shared secret
+fn has_shared_salt(encoded: &str) -> bool {
+    let shared_salt = base64::encode_config(SECRET.as_bytes(), base64::STANDARD_NO_PAD);
+    encoded.split('$').nth(4) == Some(shared_salt.as_str())
+}
+
+/// The configured pepper and the one it replaced
+#[derive(Clone, Copy)]
+struct Peppers<'a> {
+    current: Option<&'a str>,
+    previous: Option<&'a str>,
+}
+
+/// The peppers configured with `PASSWORD_PEPPER` and `PASSWORD_PEPPER_PREVIOUS`
+fn peppers() -> Peppers<'static> {
+    Peppers {
+        current: PEPPER.as_deref(),
+        previous: PREVIOUS_PEPPER.as_deref(),
+    }
+}
+
+/// The current argon2 configuration, with `secret` as the pepper
+fn config(secret: &[u8]) -> Config<'_> {
+    Config {
+        variant: *VARIANT,
+        version: Version::Version13,
+        mem_cost: *MEMORY,
+        time_cost: *ITERATIONS,
+        lanes: LANES,
+        thread_mode: ThreadMode::Sequential,
+        secret,
+        ad: &[],
+        hash_length: 32,
+    }
+}
+
+/// The result of successfully checking a password against a hash
+pub enum PasswordMatch {
+    /// The hash was produced with the current parameters
+    Current,
+    /// The password is correct but the hash should be replaced with one using the current
+    /// parameters
+    Outdated,
+}
+
+/// Hash `password` with a random salt (and the pepper if one is configured, marking the hash with
+/// its id) using the current argon2 configuration
+pub fn hash_password(password: &str) -> Result<String, Error> {
+    hash_peppered(password, PEPPER.as_deref())
+}
+
+fn hash_peppered(password: &str, pepper: Option<&str>) -> Result<String, Error> {
+    let mut salt = [0u8; 16];
+    rand::thread_rng().fill_bytes(&mut salt);
+    let config = config(pepper.unwrap_or("").as_bytes());
+    let encoded = argon2::hash_encoded(password.as_bytes(), &salt, &config).map_err(|e| {
+        eprintln!("Password Hashing Error: {}", e);
+        internal_server::ErrorVariants::AuthError.to_error()
+    })?;
+    Ok(match pepper {
+        Some(pepper) => format!("{}{}{}", PEPPER_PREFIX, pepper_id(pepper), encoded),
+        None => encoded,
+    })
+}
+
+/// Check `password` against a stored hash, returning an `Error` if it is incorrect. Only the one
+/// pepper the hash was made with is tried, so a wrong password costs a single argon2 verification
+pub fn verify_password(password: &str, hash: &str) -> Result<PasswordMatch, Error> {
+    verify_peppered(password, hash, peppers())
+}
+
+fn verify_peppered(password: &str, hash: &str, peppers: Peppers) -> Result<PasswordMatch, Error> {
+    let (id, encoded) = split_pepper(hash);
+    let matches_id = |pepper: Option<&str>| pepper.map(pepper_id).as_deref() == id;
+    // The pepper the hash was made with and whether it's the current one (or no pepper when none
+    // is configured)
+    let (pepper, current_pepper) = match id {
+        Some(_) if matches_id(peppers.current) => (peppers.current, true),
+        Some(_) if matches_id(peppers.previous) => (peppers.previous, false),
+        Some(id) => {
+            eprintln!(
+                "Password Verification Error: no configured pepper has the id {}",
+                id
+            );
+            return Err(internal_server::ErrorVariants::AuthError.to_error());
+        }
+        // Hashes without an id were made without a pepper (before one was configured)
+        None => (None, peppers.current.is_none()),
+    };
+    let secret = pepper.unwrap_or("").as_bytes();
+    match argon2::verify_encoded_ext(encoded, password.as_bytes(), secret, &[]) {
+        Ok(true) if current_pepper && !is_outdated(encoded) => Ok(PasswordMatch::Current),
+        Ok(true) => Ok(PasswordMatch::Outdated),
+        Ok(false) => Err(auth::ErrorVariants::IncorrectPassword.to_error()),
+        Err(e) => {
+            eprintln!("Password Verification Error: {}", e);
+            Err(internal_server::ErrorVariants::AuthError.to_error())
+        }
+    }
+}
+
+/// Whether an encoded hash (`$<variant>$v=<version>$m=<mem>,t=<time>,p=<lanes>$<salt>$<hash>`)
+/// differs from what `hash_password` would produce now
+fn is_outdated(encoded: &str) -> bool {
+    let parts: Vec<&str> = encoded.split('$').collect();
+    if let [_, variant, version, params, _, _] = parts[..] {
+        let current = config(&[]);
+        let current_params = format!(
+            "m={},t={},p={}",
+            current.mem_cost, current.time_cost, current.lanes
+        );
+        // Hashes salted with the old shared secret must be replaced
+        variant != current.variant.as_lowercase_str()
+            || version != format!("v={}", current.version.as_u32())
+            || params != current_params
+            || has_shared_salt(encoded)
+    } else {
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PASSWORD: &str = "Correct-Horse-9";
+
+    fn with_peppers<'a>(current: Option<&'a str>, previous: Option<&'a str>) -> Peppers<'a> {
+        Peppers { current, previous }
+    }
+
+    fn verify(password: &str, hash: &str, peppers: Peppers) -> Result<PasswordMatch, &'static str> {
+        verify_peppered(password, hash, peppers).map_err(|e| e.variant())
+    }
+
+    #[test]
+    fn unpeppered_hashes_are_upgraded_once_a_pepper_is_configured() {
+        dotenv::dotenv().ok();
+        let hash = hash_peppered(PASSWORD, None).unwrap();
+        assert!(!hash.starts_with(PEPPER_PREFIX));
+        let no_pepper = with_peppers(None, None);
+        assert!(matches!(
+            verify(PASSWORD, &hash, no_pepper),
+            Ok(PasswordMatch::Current)
+        ));
+        assert_eq!(
+            verify("Wrong-Horse-9", &hash, no_pepper).err(),
+            Some("IncorrectPassword")
+        );
+        assert!(matches!(
+            verify(PASSWORD, &hash, with_peppers(Some("pepper"), None)),
+            Ok(PasswordMatch::Outdated)
+        ));
+    }
+
+    #[test]
+    fn hashes_made_with_the_previous_pepper_verify_during_rotation() {
+        dotenv::dotenv().ok();
+        let old_hash = hash_peppered(PASSWORD, Some("old")).unwrap();
+        assert!(old_hash.starts_with(&format!("{}{}$", PEPPER_PREFIX, pepper_id("old"))));
+        assert!(matches!(
+            verify(PASSWORD, &old_hash, with_peppers(Some("old"), None)),
+            Ok(PasswordMatch::Current)
+        ));
+        let rotating = with_peppers(Some("new"), Some("old"));
+        assert!(matches!(
+            verify(PASSWORD, &old_hash, rotating),
+            Ok(PasswordMatch::Outdated)
+        ));
+        assert_eq!(
+            verify("Wrong-Horse-9", &old_hash, rotating).err(),
+            Some("IncorrectPassword")
+        );
+        let new_hash = hash_peppered(PASSWORD, Some("new")).unwrap();
+        assert!(matches!(
+            verify(PASSWORD, &new_hash, rotating),
+            Ok(PasswordMatch::Current)
+        ));
+        // Once the old pepper is gone its hashes can't be checked at all
+        assert_eq!(
+            verify(PASSWORD, &old_hash, with_peppers(Some("new"), None)).err(),
+            Some("AuthError")
+        );
+    }
+
+    #[test]
+    fn outdated_parameters_and_the_shared_salt_are_upgraded() {
+        dotenv::dotenv().ok();
+        let current = hash_peppered(PASSWORD, None).unwrap();
+        assert!(!is_outdated(&current));
+        let weaker = argon2::hash_encoded(
+            PASSWORD.as_bytes(),
+            b"0123456789abcdef",
+            &Config {
+                mem_cost: 4096,
+                time_cost: 1,
+                ..config(&[])
+            },
+        )
+        .unwrap();
+        assert!(is_outdated(&weaker));
+        assert!(matches!(
+            verify(PASSWORD, &weaker, with_peppers(None, None)),
+            Ok(PasswordMatch::Outdated)
+        ));
+        let shared_salt =
+            argon2::hash_encoded(PASSWORD.as_bytes(), SECRET.as_bytes(), &config(&[])).unwrap();
+        assert!(has_shared_salt(&shared_salt));
+        assert!(matches!(
+            verify(PASSWORD, &shared_salt, with_peppers(None, None)),
+            Ok(PasswordMatch::Outdated)
+        ));
+    }
+}
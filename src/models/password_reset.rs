@@ -0,0 +1,81 @@
+use super::session::{store_error, RedisPool};
+use crate::errors::{auth, Error};
+use rand::prelude::*;
+use redis::Commands;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+
+/// Reset token life in seconds
+const RESET_TOKEN_LIFE: usize = 30 * 60;
+
+#[derive(Deserialize)]
+/// Request body for requesting a password reset email
+pub struct PasswordResetRequest {
+    pub email: String,
+}
+
+#[derive(Deserialize)]
+/// Request body for completing a password reset
+pub struct PasswordResetComplete {
+    pub token: String,
+    pub password: String,
+}
+
+/// Hash a reset token so that the raw token never has to be stored
+fn hash_token(token: &str) -> String {
+    format!("{:x}", Sha256::digest(token.as_bytes()))
+}
+
+/// Issue a short-lived, single use password reset token for the user with an id of `id`,
+/// invalidating any token they were issued before
+pub fn issue_reset_token(conn: &RedisPool, id: uuid::Uuid) -> Result<String, Error> {
+    // Generate 32 byte long buffer of random bytes and convert it to a base64 string
+    let mut bytes: [u8; 32] = [0; 32];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    let token = base64::encode_config(bytes, base64::URL_SAFE_NO_PAD);
+    let hash = hash_token(&token);
+
+    let mut conn = conn.get().map_err(store_error)?;
+    let previous: Option<String> = conn
+        .get(format!("password_reset_user:{}", id))
+        .map_err(store_error)?;
+    let mut pipe = redis::pipe();
+    pipe.atomic();
+    if let Some(previous) = previous {
+        pipe.del(format!("password_reset:{}", previous)).ignore();
+    }
+    pipe.set_ex(
+        format!("password_reset:{}", hash),
+        id.to_string(),
+        RESET_TOKEN_LIFE,
+    )
+    .ignore()
+    .set_ex(
+        format!("password_reset_user:{}", id),
+        &hash,
+        RESET_TOKEN_LIFE,
+    )
+    .ignore();
+    pipe.query::<()>(&mut *conn).map_err(store_error)?;
+    Ok(token)
+}
+
+/// Use up a password reset token, returning the id of the user it was issued to
+pub fn consume_reset_token(conn: &RedisPool, token: &str) -> Result<uuid::Uuid, Error> {
+    let key = format!("password_reset:{}", hash_token(token));
+    let mut conn = conn.get().map_err(store_error)?;
+    // Get and delete in one go, so only the first request to get here wins
+    let (id,): (Option<String>,) = redis::pipe()
+        .atomic()
+        .get(&key)
+        .del(&key)
+        .ignore()
+        .query(&mut *conn)
+        .map_err(store_error)?;
+    let id = id
+        .and_then(|id| uuid::Uuid::parse_str(&id).ok())
+        .ok_or_else(|| auth::ErrorVariants::InvalidResetToken.to_error())?;
+    conn.del::<_, ()>(format!("password_reset_user:{}", id))
+        .map_err(store_error)?;
+    Ok(id)
+}
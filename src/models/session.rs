@@ -0,0 +1,585 @@
+use crate::errors::{auth, internal_server, Error};
+use lazy_static::lazy_static;
+use rand::prelude::*;
+use redis::Commands;
+use serde::{Deserialize, Serialize};
+use std::{
+    env,
+    time::{SystemTime, UNIX_EPOCH},
+};
+use woothee::parser::Parser;
+
+pub type RedisPool = r2d2::Pool<redis::Client>;
+type RedisConn = r2d2::PooledConnection<redis::Client>;
+
+/// Session life in seconds
+const SESSION_LIFE: u64 = 24 * 60 * 60;
+
+lazy_static! {
+    /// User Agent parser
+    static ref UA_PARSER: Parser = Parser::new();
+    /// The maximum number of concurrent sessions a user can have, when a new session would exceed
+    /// this the oldest session(s) are evicted
+    static ref MAX_SESSIONS: usize = env::var("MAX_SESSIONS")
+        .ok()
+        .and_then(|max| max.parse().ok())
+        .filter(|max| *max > 0)
+        .unwrap_or(10);
+    /// Atomically stores a new session, prunes expired sessions from the user's index and evicts
+    /// the oldest sessions over the limit, returning how many sessions were evicted
+    ///
+    /// KEYS: session key, refresh token key, user's session index
+    /// ARGV: session JSON, session id, TTL, expiry, current time, maximum number of sessions
+    static ref CREATE_SESSION: redis::Script = redis::Script::new(
+        r#"
+        redis.call('SET', KEYS[1], ARGV[1], 'EX', ARGV[3])
+        redis.call('SET', KEYS[2], ARGV[2], 'EX', ARGV[3])
+        redis.call('ZREMRANGEBYSCORE', KEYS[3], '-inf', ARGV[5])
+        redis.call('ZADD', KEYS[3], ARGV[4], ARGV[2])
+        redis.call('EXPIRE', KEYS[3], ARGV[3])
+        local evicted = redis.call('ZRANGE', KEYS[3], 0, -(tonumber(ARGV[6]) + 1))
+        for _, id in ipairs(evicted) do
+            local session = redis.call('GET', 'session:' .. id)
+            if session then
+                redis.call('DEL', 'refresh_tokens:' .. cjson.decode(session).token)
+            end
+            redis.call('DEL', 'session:' .. id)
+            redis.call('ZREM', KEYS[3], id)
+        end
+        return #evicted
+        "#
+    );
+    /// Atomically swaps a session's refresh token for a new one, remembering the old token (for as
+    /// long as the session lives) so that it being presented again can be detected
+    ///
+    /// KEYS: old refresh token key, new refresh token key, old token's rotated key
+    /// ARGV: new refresh token, current time, client IP (empty if unknown)
+    /// Returns: `{"rotated", session JSON}`, `{"reused", session id}` or `{"invalid", ""}`
+    static ref ROTATE_TOKEN: redis::Script = redis::Script::new(
+        r#"
+        local session_id = redis.call('GET', KEYS[1])
+        if not session_id then
+            local family = redis.call('GET', KEYS[3])
+            if family then
+                return {'reused', family}
+            end
+            return {'invalid', ''}
+        end
+        local key = 'session:' .. session_id
+        local session = redis.call('GET', key)
+        local ttl = redis.call('TTL', key)
+        if not session or ttl <= 0 then
+            return {'invalid', ''}
+        end
+        local decoded = cjson.decode(session)
+        decoded.token = ARGV[1]
+        decoded.last_refreshed_at = tonumber(ARGV[2])
+        if ARGV[3] ~= '' then
+            decoded.ip = ARGV[3]
+        end
+        session = cjson.encode(decoded)
+        redis.call('SET', key, session, 'EX', ttl)
+        redis.call('DEL', KEYS[1])
+        redis.call('SET', KEYS[2], session_id, 'EX', ttl)
+        redis.call('SET', KEYS[3], session_id, 'EX', ttl)
+        return {'rotated', session}
+        "#
+    );
+    /// Atomically sets (or clears) a session's device name, keeping its TTL
+    ///
+    /// KEYS: session key
+    /// ARGV: id of the user the session has to belong to, device name (empty to clear it)
+    /// Returns: 1 if the session was updated, 0 if it doesn't exist (or isn't the user's)
+    static ref RENAME_SESSION: redis::Script = redis::Script::new(
+        r#"
+        local session = redis.call('GET', KEYS[1])
+        local ttl = redis.call('TTL', KEYS[1])
+        if not session or ttl <= 0 then
+            return 0
+        end
+        local decoded = cjson.decode(session)
+        if decoded.id ~= ARGV[1] then
+            return 0
+        end
+        if ARGV[2] == '' then
+            decoded.device_name = nil
+        else
+            decoded.device_name = ARGV[2]
+        end
+        redis.call('SET', KEYS[1], cjson.encode(decoded), 'EX', ttl)
+        return 1
+        "#
+    );
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+/// Represents a user's session as stored in redis (under "session:{session_id}", with the user's
+/// session ids indexed in the sorted set "sessions:{id}" scored by expiry)
+pub struct UserSession {
+    /// The id of the user the session belongs to
+    pub id: uuid::Uuid,
+    /// Identifies the session without exposing the refresh token, this is also the id of the
+    /// refresh token family (every token the session has rotated through)
+    pub session_id: uuid::Uuid,
+    pub token: String,
+    pub os: String,
+    pub browser: String,
+    pub expiry: u64,
+    /// IP address the session was last used from (when the session was created or refreshed)
+    #[serde(default)]
+    pub ip: Option<String>,
+    #[serde(default)]
+    pub created_at: u64,
+    #[serde(default)]
+    pub last_refreshed_at: u64,
+    /// Name the user has given the device (to tell their sessions apart)
+    #[serde(default)]
+    pub device_name: Option<String>,
+}
+
+/// What presenting a refresh token to `UserSession::rotate` did
+pub enum Rotation {
+    /// The token was swapped for a new one
+    Rotated(UserSession),
+    /// The token had already been rotated, holds the id of the user whose session was revoked
+    /// because of it (if the session still existed)
+    Reused(Option<uuid::Uuid>),
+}
+
+#[derive(Deserialize)]
+/// Request body for naming a session's device, a missing or empty name clears it
+pub struct SessionUpdate {
+    pub device_name: Option<String>,
+}
+
+impl UserSession {
+    /// Construct `UserSession` from user's id, a randomly generated session/refresh token, their
+    /// useragent and IP (as a means of rough identification) and return it
+    pub fn new(id: uuid::Uuid, useragent: &str, ip: Option<String>) -> Self {
+        let token = generate_token();
+        let session_id = uuid::Uuid::new_v4();
+        let (os, browser) = parse_user_agent(useragent);
+        // Get the current epoch time and add on the session life
+        let created_at = now();
+        let expiry = created_at + SESSION_LIFE;
+        UserSession {
+            id,
+            session_id,
+            token,
+            os,
+            browser,
+            expiry,
+            ip,
+            created_at,
+            last_refreshed_at: created_at,
+            device_name: None,
+        }
+    }
+
+    /// Save `self` to redis (evicting the user's oldest sessions if they have too many) and return
+    /// the session/refresh token
+    pub fn set_session(&self, conn: &RedisPool) -> Result<String, Error> {
+        let mut conn = conn.get().map_err(store_error)?;
+        let session = serde_json::to_string(self).map_err(store_error)?;
+        let evicted: usize = CREATE_SESSION
+            .key(format!("session:{}", self.session_id))
+            .key(format!("refresh_tokens:{}", self.token))
+            .key(format!("sessions:{}", self.id))
+            .arg(session)
+            .arg(self.session_id.to_string())
+            .arg(SESSION_LIFE)
+            .arg(self.expiry)
+            .arg(now())
+            .arg(*MAX_SESSIONS)
+            .invoke(&mut *conn)
+            .map_err(store_error)?;
+        if evicted > 0 {
+            eprintln!(
+                "Evicted {} session(s) for user {} (limit is {})",
+                evicted, self.id, *MAX_SESSIONS
+            );
+        }
+        Ok(self.token.clone())
+    }
+
+    /// Find the session that a refresh token belongs to, returning an `Error` if the token is
+    /// unknown or the session has expired
+    pub fn get_by_token(conn: &RedisPool, token: &str) -> Result<Self, Error> {
+        let mut conn = conn.get().map_err(store_error)?;
+        // Look up the id of the session that the token was issued for
+        let session_id: Option<String> = conn
+            .get(format!("refresh_tokens:{}", token))
+            .map_err(store_error)?;
+        let session_id =
+            session_id.ok_or_else(|| auth::ErrorVariants::InvalidRefreshToken.to_error())?;
+        let session: Option<String> = conn
+            .get(format!("session:{}", session_id))
+            .map_err(store_error)?;
+        let session: UserSession = match session {
+            Some(s) => serde_json::from_str(&s).map_err(store_error)?,
+            None => return Err(auth::ErrorVariants::InvalidRefreshToken.to_error()),
+        };
+        if session.token != token {
+            return Err(auth::ErrorVariants::InvalidRefreshToken.to_error());
+        }
+        // Redis should have already expired the keys, but don't rely on it
+        if session.expiry <= now() {
+            return Err(auth::ErrorVariants::SessionExpired.to_error());
+        }
+        Ok(session)
+    }
+
+    /// Number of seconds until the session expires
+    pub fn ttl(&self) -> u64 {
+        self.expiry.saturating_sub(now())
+    }
+
+    /// Exchange a refresh token for a new one, returning the session with its new token. If the
+    /// token has already been rotated then it has been stolen (or replayed), so the whole token
+    /// family (the session) is revoked. The session's last refresh time and IP are updated too
+    pub fn rotate(conn: &RedisPool, token: &str, ip: Option<String>) -> Result<Rotation, Error> {
+        let mut conn = conn.get().map_err(store_error)?;
+        let new_token = generate_token();
+        let (status, payload): (String, String) = ROTATE_TOKEN
+            .key(format!("refresh_tokens:{}", token))
+            .key(format!("refresh_tokens:{}", new_token))
+            .key(format!("rotated_refresh_tokens:{}", token))
+            .arg(&new_token)
+            .arg(now())
+            .arg(ip.unwrap_or_default())
+            .invoke(&mut *conn)
+            .map_err(store_error)?;
+        match status.as_str() {
+            "rotated" => serde_json::from_str(&payload)
+                .map(Rotation::Rotated)
+                .map_err(store_error),
+            "reused" => {
+                let session: Option<String> = conn
+                    .get(format!("session:{}", payload))
+                    .map_err(store_error)?;
+                match session {
+                    Some(session) => {
+                        let session: UserSession =
+                            serde_json::from_str(&session).map_err(store_error)?;
+                        eprintln!(
+                            "Security Event: rotated refresh token reused, revoking session {} for user {}",
+                            session.session_id, session.id
+                        );
+                        let id = session.id;
+                        remove_sessions(&mut conn, id, &[session])?;
+                        Ok(Rotation::Reused(Some(id)))
+                    }
+                    None => Ok(Rotation::Reused(None)),
+                }
+            }
+            _ => Err(auth::ErrorVariants::InvalidRefreshToken.to_error()),
+        }
+    }
+
+    /// Get all of a user's sessions that haven't expired yet
+    pub fn get_all(conn: &RedisPool, id: uuid::Uuid) -> Result<Vec<Self>, Error> {
+        let mut conn = conn.get().map_err(store_error)?;
+        let now = now();
+        Ok(get_sessions(&mut conn, id)?
+            .into_iter()
+            .filter(|s| s.expiry > now)
+            .collect())
+    }
+
+    /// Revoke the session with an id of `session_id` belonging to the user with an id of `id`,
+    /// returning an `Error` if there is no such session
+    pub fn revoke(conn: &RedisPool, id: uuid::Uuid, session_id: uuid::Uuid) -> Result<(), Error> {
+        let mut conn = conn.get().map_err(store_error)?;
+        let session: Option<String> = conn
+            .get(format!("session:{}", session_id))
+            .map_err(store_error)?;
+        let session: UserSession = match session {
+            Some(s) => serde_json::from_str(&s).map_err(store_error)?,
+            None => return Err(auth::ErrorVariants::SessionNotFound.to_error()),
+        };
+        // Don't let users revoke each other's sessions
+        if session.id != id {
+            return Err(auth::ErrorVariants::SessionNotFound.to_error());
+        }
+        remove_sessions(&mut conn, id, &[session])
+    }
+
+    /// Set (or clear, if `device_name` is `None`) the device name of the session with an id of
+    /// `session_id` belonging to the user with an id of `id`
+    pub fn rename(
+        conn: &RedisPool,
+        id: uuid::Uuid,
+        session_id: uuid::Uuid,
+        device_name: Option<&str>,
+    ) -> Result<(), Error> {
+        let mut conn = conn.get().map_err(store_error)?;
+        let updated: usize = RENAME_SESSION
+            .key(format!("session:{}", session_id))
+            .arg(id.to_string())
+            .arg(device_name.unwrap_or_default())
+            .invoke(&mut *conn)
+            .map_err(store_error)?;
+        if updated == 0 {
+            return Err(auth::ErrorVariants::SessionNotFound.to_error());
+        }
+        Ok(())
+    }
+
+    /// Revoke every one of a user's sessions
+    pub fn revoke_all(conn: &RedisPool, id: uuid::Uuid) -> Result<(), Error> {
+        let mut conn = conn.get().map_err(store_error)?;
+        let sessions = get_sessions(&mut conn, id)?;
+        remove_sessions(&mut conn, id, &sessions)?;
+        conn.del::<String, ()>(format!("sessions:{}", id))
+            .map_err(store_error)
+    }
+    /// Revoke every one of a user's sessions apart from the one with an id of `keep`
+    pub fn revoke_all_except(
+        conn: &RedisPool,
+        id: uuid::Uuid,
+        keep: uuid::Uuid,
+    ) -> Result<(), Error> {
+        let mut conn = conn.get().map_err(store_error)?;
+        let sessions: Vec<UserSession> = get_sessions(&mut conn, id)?
+            .into_iter()
+            .filter(|s| s.session_id != keep)
+            .collect();
+        remove_sessions(&mut conn, id, &sessions)
+    }
+}
+
+#[derive(Serialize)]
+/// Response body when logging in or refreshing in bearer mode, with both tokens and when they
+/// expire (unix epoch time in seconds)
+pub struct BearerTokens {
+    pub token_type: &'static str,
+    pub access_token: String,
+    pub access_token_expires_at: usize,
+    pub refresh_token: String,
+    pub refresh_token_expires_at: u64,
+}
+
+/// Parse a user agent into its OS and browser, both are "Unknown" if the user agent is invalid
+pub fn parse_user_agent(useragent: &str) -> (String, String) {
+    match UA_PARSER.parse(useragent) {
+        Some(parsed) => (parsed.os.to_string(), parsed.browser_type.to_string()),
+        None => ("Unknown".to_string(), "Unknown".to_string()),
+    }
+}
+
+/// Generate a random session/refresh token
+fn generate_token() -> String {
+    // Generate 48 byte long buffer of random bytes
+    let mut bytes: [u8; 48] = [0; 48];
+    let mut rng = rand::thread_rng();
+    rng.fill_bytes(&mut bytes);
+    // Convert the buffer to a base64 string
+    base64::encode_config(bytes, base64::URL_SAFE)
+}
+
+/// Current unix epoch time in seconds
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+/// Log a session store (redis) error and convert it into an `Error` for the client
+pub(super) fn store_error(e: impl std::fmt::Display) -> Error {
+    eprintln!("Redis Error: {}", e);
+    internal_server::ErrorVariants::SessionStoreError.to_error()
+}
+
+/// Prune expired session ids from a user's index and then grab and deserialise the rest of their
+/// sessions
+fn get_sessions(conn: &mut RedisConn, id: uuid::Uuid) -> Result<Vec<UserSession>, Error> {
+    let index = format!("sessions:{}", id);
+    conn.zrembyscore::<_, _, _, ()>(&index, "-inf", now())
+        .map_err(store_error)?;
+    let session_ids: Vec<String> = conn.zrange(&index, 0, -1).map_err(store_error)?;
+    if session_ids.is_empty() {
+        return Ok(vec![]);
+    }
+    let keys: Vec<String> = session_ids
+        .iter()
+        .map(|session_id| format!("session:{}", session_id))
+        .collect();
+    // `MGET` gives back `nil` for sessions which redis has already expired
+    let sessions: Vec<Option<String>> = redis::cmd("MGET")
+        .arg(keys)
+        .query(&mut **conn)
+        .map_err(store_error)?;
+    sessions
+        .into_iter()
+        .flatten()
+        .map(|s| serde_json::from_str(&s).map_err(store_error))
+        .collect()
+}
+
+/// Atomically delete `sessions` along with their refresh tokens and remove them from the user's
+/// index
+fn remove_sessions(
+    conn: &mut RedisConn,
+    id: uuid::Uuid,
+    sessions: &[UserSession],
+) -> Result<(), Error> {
+    let mut pipe = redis::pipe();
+    pipe.atomic();
+    for session in sessions {
+        pipe.del(format!("session:{}", session.session_id))
+            .ignore()
+            .del(format!("refresh_tokens:{}", session.token))
+            .ignore()
+            .zrem(format!("sessions:{}", id), session.session_id.to_string())
+            .ignore();
+    }
+    pipe.query(&mut **conn).map_err(store_error)
+}
+
+#[derive(Serialize)]
+/// A user's session as sent to the client (the refresh token is omitted)
+pub struct UserSessionInfo {
+    pub session_id: uuid::Uuid,
+    pub os: String,
+    pub browser: String,
+    pub expiry: u64,
+    pub ip: Option<String>,
+    pub created_at: u64,
+    pub last_refreshed_at: u64,
+    pub device_name: Option<String>,
+    /// Whether this is the session that the request was made with
+    pub current: bool,
+}
+
+impl UserSessionInfo {
+    /// Construct `UserSessionInfo` from a `UserSession`, marking it as current if its id matches
+    /// `current_id`
+    pub fn from_session(session: UserSession, current_id: uuid::Uuid) -> Self {
+        UserSessionInfo {
+            current: session.session_id == current_id,
+            session_id: session.session_id,
+            os: session.os,
+            browser: session.browser,
+            expiry: session.expiry,
+            ip: session.ip,
+            created_at: session.created_at,
+            last_refreshed_at: session.last_refreshed_at,
+            device_name: session.device_name,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const USER_AGENT: &str =
+        "Mozilla/5.0 (X11; Linux x86_64; rv:109.0) Gecko/20100101 Firefox/115.0";
+
+    fn error_kind<T>(res: Result<T, Error>) -> Option<&'static str> {
+        res.err().map(|e| e.variant())
+    }
+
+    #[test]
+    #[ignore = "needs a redis server at REDIS_CONN"]
+    fn reusing_a_rotated_refresh_token_revokes_the_session() {
+        let pool = super::super::test_redis_pool();
+        let id = uuid::Uuid::new_v4();
+        let session = UserSession::new(id, USER_AGENT, Some("203.0.113.7".to_string()));
+        let first_token = session.set_session(&pool).unwrap();
+
+        let rotated = match UserSession::rotate(&pool, &first_token, Some("203.0.113.8".into())) {
+            Ok(Rotation::Rotated(rotated)) => rotated,
+            _ => panic!("the refresh token wasn't rotated"),
+        };
+        assert_eq!(rotated.session_id, session.session_id);
+        assert_ne!(rotated.token, first_token);
+        assert_eq!(rotated.ip.as_deref(), Some("203.0.113.8"));
+        assert_eq!(
+            error_kind(UserSession::get_by_token(&pool, &first_token)),
+            Some("InvalidRefreshToken")
+        );
+        assert!(UserSession::get_by_token(&pool, &rotated.token).is_ok());
+
+        // Presenting the old token again means it was stolen, so the whole family goes
+        assert!(matches!(
+            UserSession::rotate(&pool, &first_token, None),
+            Ok(Rotation::Reused(Some(user))) if user == id
+        ));
+        assert_eq!(
+            error_kind(UserSession::get_by_token(&pool, &rotated.token)),
+            Some("InvalidRefreshToken")
+        );
+        assert!(UserSession::get_all(&pool, id).unwrap().is_empty());
+        // The revoked session's tokens stay unusable
+        assert!(matches!(
+            UserSession::rotate(&pool, &first_token, None),
+            Ok(Rotation::Reused(None))
+        ));
+        assert_eq!(
+            error_kind(UserSession::rotate(&pool, &rotated.token, None)),
+            Some("InvalidRefreshToken")
+        );
+    }
+
+    #[test]
+    #[ignore = "needs a redis server at REDIS_CONN"]
+    fn the_oldest_sessions_are_evicted_over_the_cap() {
+        let pool = super::super::test_redis_pool();
+        let id = uuid::Uuid::new_v4();
+        let count = *MAX_SESSIONS + 2;
+        let sessions: Vec<UserSession> = (0..count)
+            .map(|i| {
+                let mut session = UserSession::new(id, USER_AGENT, None);
+                // Older sessions expire first, which is what the index is ordered by
+                session.expiry -= (count - i) as u64;
+                session.set_session(&pool).unwrap();
+                session
+            })
+            .collect();
+
+        let mut kept: Vec<uuid::Uuid> = UserSession::get_all(&pool, id)
+            .unwrap()
+            .into_iter()
+            .map(|s| s.session_id)
+            .collect();
+        kept.sort();
+        let mut newest: Vec<uuid::Uuid> = sessions[2..].iter().map(|s| s.session_id).collect();
+        newest.sort();
+        assert_eq!(kept, newest);
+        for evicted in &sessions[..2] {
+            assert_eq!(
+                error_kind(UserSession::get_by_token(&pool, &evicted.token)),
+                Some("InvalidRefreshToken")
+            );
+        }
+
+        // Every key expires along with the session and the index is scored by expiry
+        let mut conn = pool.get().unwrap();
+        let newest = &sessions[count - 1];
+        let index = format!("sessions:{}", id);
+        let index_ttl: i64 = conn.ttl(&index).unwrap();
+        assert!(index_ttl > 0 && index_ttl <= SESSION_LIFE as i64);
+        let session_ttl: i64 = conn.ttl(format!("session:{}", newest.session_id)).unwrap();
+        assert!(session_ttl > 0 && session_ttl <= SESSION_LIFE as i64);
+        let token_ttl: i64 = conn
+            .ttl(format!("refresh_tokens:{}", newest.token))
+            .unwrap();
+        assert!(token_ttl > 0 && token_ttl <= SESSION_LIFE as i64);
+        let score: Option<u64> = conn.zscore(&index, newest.session_id.to_string()).unwrap();
+        assert_eq!(score, Some(newest.expiry));
+
+        // Expired entries are pruned from the index when a session is created
+        conn.zadd::<_, _, _, ()>(&index, "expired", now() - 1)
+            .unwrap();
+        UserSession::new(id, USER_AGENT, None)
+            .set_session(&pool)
+            .unwrap();
+        let expired: Option<u64> = conn.zscore(&index, "expired").unwrap();
+        assert_eq!(expired, None);
+
+        UserSession::revoke_all(&pool, id).unwrap();
+        assert!(UserSession::get_all(&pool, id).unwrap().is_empty());
+    }
+}
@@ -0,0 +1,267 @@
+use super::user::JWT_SECRET;
+use jsonwebtoken::{errors::ErrorKind, Algorithm, DecodingKey, EncodingKey, Header, Validation};
+use lazy_static::lazy_static;
+use openssl::pkey::{Id, PKey};
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::{json, Value};
+use std::{env, fs};
+
+lazy_static! {
+    /// Keys used to sign and verify access tokens, configured with `JWT_SIGNING_KEYS` (a comma
+    /// separated list of `<kid>:<path to PEM file>`). The first key signs new tokens and must be a
+    /// private key, the rest are only used to verify tokens (so they can be public keys) which
+    /// lets a new key be rolled out without invalidating tokens signed by the old one. RSA
+    /// (RS256) and Ed25519 (EdDSA) keys are supported, if no keys are configured tokens are
+    /// signed with `JWT_SECRET` (HS256) instead
+    static ref KEYS: Vec<SigningKey> = env::var("JWT_SIGNING_KEYS")
+        .unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .filter(|key| !key.is_empty())
+        .enumerate()
+        .map(|(i, key)| SigningKey::from_config(key, i == 0))
+        .collect();
+}
+
+/// An asymmetric key that access tokens are signed with
+struct SigningKey {
+    kid: String,
+    algorithm: Algorithm,
+    /// Only loaded for the active (first) key
+    encoding: Option<EncodingKey>,
+    decoding: DecodingKey,
+    /// The public key in JWK form, as served from the JWKS endpoint
+    jwk: Value,
+}
+
+fn base64url(bytes: &[u8]) -> String {
+    base64::encode_config(bytes, base64::URL_SAFE_NO_PAD)
+}
+
+impl SigningKey {
+    /// Load a key from a `<kid>:<path>` config entry, panicking if it can't be used since the
+    /// server can't issue or check tokens without its keys
+    fn from_config(config: &str, is_active: bool) -> Self {
+        let (kid, path) = match config.find(':') {
+            Some(i) => (&config[..i], &config[i + 1..]),
+            None => panic!("JWT_SIGNING_KEYS entry \"{}\" isn't <kid>:<path>", config),
+        };
+        let pem = fs::read(path)
+            .unwrap_or_else(|e| panic!("Failed to read JWT signing key {}: {}", path, e));
+        Self::from_pem(kid, &pem, is_active)
+    }
+
+    /// Load the key `kid` from a PEM file's contents, the active key has to be a private key
+    fn from_pem(kid: &str, pem: &[u8], is_active: bool) -> Self {
+        let private = PKey::private_key_from_pem(pem).ok();
+        let public = match &private {
+            Some(key) => PKey::public_key_from_der(&key.public_key_to_der().unwrap()),
+            None => PKey::public_key_from_pem(pem),
+        }
+        .unwrap_or_else(|e| panic!("JWT signing key {} isn't a valid PEM key: {}", kid, e));
+        if is_active && private.is_none() {
+            panic!("The first JWT signing key ({}) must be a private key", kid);
+        }
+        let (algorithm, encoding, decoding, jwk) = match public.id() {
+            Id::RSA => {
+                let rsa = public.rsa().unwrap();
+                let (n, e) = (base64url(&rsa.n().to_vec()), base64url(&rsa.e().to_vec()));
+                (
+                    Algorithm::RS256,
+                    if is_active {
+                        Some(EncodingKey::from_rsa_pem(pem).unwrap_or_else(|e| {
+                            panic!("Invalid RSA JWT signing key {}: {}", kid, e)
+                        }))
+                    } else {
+                        None
+                    },
+                    DecodingKey::from_rsa_components(&n, &e).unwrap(),
+                    json!({ "kty": "RSA", "alg": "RS256", "n": n, "e": e }),
+                )
+            }
+            Id::ED25519 => {
+                let x = base64url(&public.raw_public_key().unwrap());
+                (
+                    Algorithm::EdDSA,
+                    if is_active {
+                        Some(EncodingKey::from_ed_pem(pem).unwrap_or_else(|e| {
+                            panic!("Invalid Ed25519 JWT signing key {}: {}", kid, e)
+                        }))
+                    } else {
+                        None
+                    },
+                    DecodingKey::from_ed_components(&x).unwrap(),
+                    json!({ "kty": "OKP", "crv": "Ed25519", "alg": "EdDSA", "x": x }),
+                )
+            }
+            _ => panic!("JWT signing key {} must be an RSA or Ed25519 key", kid),
+        };
+        let mut jwk = jwk;
+        jwk["kid"] = json!(kid);
+        jwk["use"] = json!("sig");
+        SigningKey {
+            kid: kid.to_string(),
+            algorithm,
+            encoding,
+            decoding,
+            jwk,
+        }
+    }
+}
+
+/// Sign `claims` with the active key
+pub fn sign<T: Serialize>(claims: &T) -> String {
+    sign_with(&KEYS, claims)
+}
+
+fn sign_with<T: Serialize>(keys: &[SigningKey], claims: &T) -> String {
+    match keys.first() {
+        Some(key) => jsonwebtoken::encode(
+            &Header {
+                kid: Some(key.kid.clone()),
+                ..Header::new(key.algorithm)
+            },
+            claims,
+            key.encoding.as_ref().unwrap(),
+        ),
+        None => jsonwebtoken::encode(
+            &Header::default(),
+            claims,
+            &EncodingKey::from_secret(JWT_SECRET.as_ref()),
+        ),
+    }
+    .unwrap()
+}
+
+/// Check a token's signature (with the key its "kid" header points to) and expiry, returning the
+/// claims within
+pub fn verify<T: DeserializeOwned>(token: &str) -> jsonwebtoken::errors::Result<T> {
+    verify_with(&KEYS, token)
+}
+
+fn verify_with<T: DeserializeOwned>(
+    keys: &[SigningKey],
+    token: &str,
+) -> jsonwebtoken::errors::Result<T> {
+    if keys.is_empty() {
+        return jsonwebtoken::decode::<T>(
+            token,
+            &DecodingKey::from_secret(JWT_SECRET.as_ref()),
+            &Validation::default(),
+        )
+        .map(|data| data.claims);
+    }
+    let header = jsonwebtoken::decode_header(token)?;
+    let key = keys
+        .iter()
+        .find(|key| header.kid.as_ref() == Some(&key.kid))
+        .ok_or(ErrorKind::InvalidSignature)?;
+    jsonwebtoken::decode::<T>(token, &key.decoding, &Validation::new(key.algorithm))
+        .map(|data| data.claims)
+}
+
+/// The public signing keys as a JWK set, so that other services can verify access tokens
+pub fn jwks() -> Value {
+    jwks_of(&KEYS)
+}
+
+fn jwks_of(keys: &[SigningKey]) -> Value {
+    json!({ "keys": keys.iter().map(|key| &key.jwk).collect::<Vec<_>>() })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use openssl::rsa::Rsa;
+    use serde::Deserialize;
+
+    #[derive(Serialize, Deserialize, PartialEq, Debug)]
+    struct Claims {
+        sub: String,
+        exp: usize,
+    }
+
+    fn claims() -> Claims {
+        Claims {
+            sub: "johnd03".to_string(),
+            exp: super::super::now() as usize + 60,
+        }
+    }
+
+    fn rsa_pem() -> Vec<u8> {
+        let key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+        key.private_key_to_pem_pkcs8().unwrap()
+    }
+
+    fn ed25519_pem() -> Vec<u8> {
+        let key = PKey::generate_ed25519().unwrap();
+        key.private_key_to_pem_pkcs8().unwrap()
+    }
+
+    fn public_pem(private_pem: &[u8]) -> Vec<u8> {
+        let key = PKey::private_key_from_pem(private_pem).unwrap();
+        key.public_key_to_pem().unwrap()
+    }
+
+    fn kid(token: &str) -> Option<String> {
+        jsonwebtoken::decode_header(token).unwrap().kid
+    }
+
+    #[test]
+    fn tokens_are_verified_with_the_key_their_kid_names() {
+        let (old, new) = (rsa_pem(), ed25519_pem());
+        let before = [SigningKey::from_pem("2024-01", &old, true)];
+        let old_token = sign_with(&before, &claims());
+        assert_eq!(kid(&old_token).as_deref(), Some("2024-01"));
+
+        // Rolling out a new key keeps the old one around to verify the tokens it signed
+        let after = [
+            SigningKey::from_pem("2024-02", &new, true),
+            SigningKey::from_pem("2024-01", &public_pem(&old), false),
+        ];
+        let new_token = sign_with(&after, &claims());
+        assert_eq!(kid(&new_token).as_deref(), Some("2024-02"));
+        assert_eq!(verify_with::<Claims>(&after, &new_token).unwrap(), claims());
+        assert_eq!(verify_with::<Claims>(&after, &old_token).unwrap(), claims());
+
+        // Tokens are rejected once their key is gone, or if their kid names another key
+        let err = verify_with::<Claims>(&after[..1], &old_token).unwrap_err();
+        assert_eq!(*err.kind(), ErrorKind::InvalidSignature);
+        let impostor = [SigningKey::from_pem("2024-02", &ed25519_pem(), true)];
+        assert!(verify_with::<Claims>(&impostor, &new_token).is_err());
+    }
+
+    #[test]
+    fn the_jwks_verifies_tokens_on_its_own() {
+        let (ed25519, rsa) = (ed25519_pem(), rsa_pem());
+        let keys = [
+            SigningKey::from_pem("ed", &ed25519, true),
+            SigningKey::from_pem("rsa", &public_pem(&rsa), false),
+        ];
+        let jwks = jwks_of(&keys);
+        let jwks = jwks["keys"].as_array().unwrap();
+        assert_eq!(jwks.len(), 2);
+        assert_eq!(jwks[0]["kid"], "ed");
+        assert_eq!(jwks[0]["kty"], "OKP");
+        assert_eq!(jwks[0]["use"], "sig");
+        assert_eq!(jwks[1]["kid"], "rsa");
+        assert_eq!(jwks[1]["kty"], "RSA");
+        assert!(jwks.iter().all(|jwk| jwk.get("d").is_none()));
+
+        let token = sign_with(&keys, &claims());
+        let key = DecodingKey::from_ed_components(jwks[0]["x"].as_str().unwrap()).unwrap();
+        let decoded =
+            jsonwebtoken::decode::<Claims>(&token, &key, &Validation::new(Algorithm::EdDSA));
+        assert_eq!(decoded.unwrap().claims, claims());
+
+        let rsa_token = sign_with(&[SigningKey::from_pem("rsa", &rsa, true)], &claims());
+        let key = DecodingKey::from_rsa_components(
+            jwks[1]["n"].as_str().unwrap(),
+            jwks[1]["e"].as_str().unwrap(),
+        )
+        .unwrap();
+        let decoded =
+            jsonwebtoken::decode::<Claims>(&rsa_token, &key, &Validation::new(Algorithm::RS256));
+        assert_eq!(decoded.unwrap().claims, claims());
+    }
+}
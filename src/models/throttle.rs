@@ -0,0 +1,200 @@
+use super::session::{store_error, RedisPool};
+use crate::errors::{auth, Error};
+use lazy_static::lazy_static;
+
+/// Window (in seconds) over which failed login attempts are counted, each attempt resets it
+const FAILURE_WINDOW: u64 = 15 * 60;
+/// Length (in seconds) of the first lockout, each failure after that doubles it
+const BASE_LOCKOUT: u64 = 30;
+/// Longest a lockout can last (in seconds)
+const MAX_LOCKOUT: u64 = 60 * 60;
+
+lazy_static! {
+    /// Atomically checks the lockouts of every throttle key and, if none of them are locked out,
+    /// counts an attempt against each of them (locking out the ones over their limit with an
+    /// exponentially increasing lockout)
+    ///
+    /// KEYS: failures key and lockout key of each throttle key
+    /// ARGV: failure window, base lockout, maximum lockout, then the maximum number of failures of
+    /// each throttle key
+    /// Returns: the number of seconds until the longest lockout ends (0 if there isn't one)
+    static ref BEGIN_ATTEMPT: redis::Script = redis::Script::new(
+        r#"
+        local retry_after = 0
+        for i = 1, #KEYS, 2 do
+            retry_after = math.max(retry_after, redis.call('TTL', KEYS[i + 1]))
+        end
+        if retry_after > 0 then
+            return retry_after
+        end
+        for i = 1, #KEYS, 2 do
+            local failures = redis.call('INCR', KEYS[i])
+            redis.call('EXPIRE', KEYS[i], ARGV[1])
+            local over = failures - tonumber(ARGV[3 + (i + 1) / 2])
+            if over > 0 then
+                local lockout = tonumber(ARGV[2]) * 2 ^ math.min(over - 1, 32)
+                lockout = math.floor(math.min(lockout, tonumber(ARGV[3])))
+                redis.call('SET', KEYS[i + 1], failures, 'EX', lockout)
+                retry_after = math.max(retry_after, lockout)
+            end
+        end
+        return retry_after
+        "#
+    );
+    /// Atomically takes back an attempt from each failures key that still exists
+    ///
+    /// KEYS: failures key of each throttle key
+    static ref FORGIVE_ATTEMPT: redis::Script = redis::Script::new(
+        r#"
+        for _, key in ipairs(KEYS) do
+            if redis.call('EXISTS', key) == 1 then
+                redis.call('DECR', key)
+            end
+        end
+        return 0
+        "#
+    );
+}
+
+/// Something that failed login attempts are counted against
+pub enum ThrottleKey<'a> {
+    /// The username of the account being logged into
+    Username(&'a str),
+    /// The IP address of the client
+    Ip(&'a str),
+}
+
+impl ThrottleKey<'_> {
+    /// Suffix of the redis keys for this throttle key
+    fn name(&self) -> String {
+        match self {
+            ThrottleKey::Username(username) => format!("user:{}", username.to_lowercase()),
+            ThrottleKey::Ip(ip) => format!("ip:{}", ip),
+        }
+    }
+
+    /// Number of failures allowed within the window before being locked out, an IP can be shared
+    /// by many (legitimate) users so it gets more leeway
+    fn max_failures(&self) -> u64 {
+        match self {
+            ThrottleKey::Username(_) => 5,
+            ThrottleKey::Ip(_) => 20,
+        }
+    }
+}
+
+/// Count an attempt against each of `keys` (before it is checked, so that concurrent attempts
+/// can't slip past the limit), returning a "too many attempts" `Error` (with the longest remaining
+/// lockout) if any of them are locked out. Attempts that turn out to be successful should be
+/// handed to `forgive_attempt`
+pub fn begin_attempt(conn: &RedisPool, keys: &[ThrottleKey]) -> Result<(), Error> {
+    let mut conn = conn.get().map_err(store_error)?;
+    let mut invocation = BEGIN_ATTEMPT.prepare_invoke();
+    invocation
+        .arg(FAILURE_WINDOW)
+        .arg(BASE_LOCKOUT)
+        .arg(MAX_LOCKOUT);
+    for key in keys {
+        invocation
+            .key(format!("login_failures:{}", key.name()))
+            .key(format!("login_lockout:{}", key.name()))
+            .arg(key.max_failures());
+    }
+    let retry_after: u64 = invocation.invoke(&mut *conn).map_err(store_error)?;
+    if retry_after > 0 {
+        Err(auth::ErrorVariants::TooManyAttempts(retry_after).to_error())
+    } else {
+        Ok(())
+    }
+}
+
+/// Take back an attempt counted by `begin_attempt` against each of `keys` once it has succeeded,
+/// any failed attempts before it are still counted
+pub fn forgive_attempt(conn: &RedisPool, keys: &[ThrottleKey]) -> Result<(), Error> {
+    let mut conn = conn.get().map_err(store_error)?;
+    let mut invocation = FORGIVE_ATTEMPT.prepare_invoke();
+    for key in keys {
+        invocation.key(format!("login_failures:{}", key.name()));
+    }
+    invocation.invoke(&mut *conn).map_err(store_error)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::errors::ErrorCategories;
+    use actix_web::{http::StatusCode, ResponseError};
+    use redis::Commands;
+
+    fn retry_after(res: Result<(), Error>) -> Option<u64> {
+        match res.map_err(|e| e.error.body) {
+            Err(ErrorCategories::AuthError(e)) => e.retry_after,
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn lockouts_are_sent_back_with_retry_after() {
+        let res = auth::ErrorVariants::TooManyAttempts(120)
+            .to_error()
+            .error_response();
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(res.headers().get("Retry-After").unwrap(), "120");
+    }
+
+    #[test]
+    #[ignore = "needs a redis server at REDIS_CONN"]
+    fn lockouts_back_off_exponentially() {
+        let pool = crate::models::test_redis_pool();
+        let username = uuid::Uuid::new_v4().to_string();
+        let keys = [ThrottleKey::Username(&username)];
+        let name = keys[0].name();
+        let lockout_key = format!("login_lockout:{}", name);
+        for _ in 0..keys[0].max_failures() {
+            assert!(begin_attempt(&pool, &keys).is_ok());
+        }
+        assert_eq!(retry_after(begin_attempt(&pool, &keys)), Some(BASE_LOCKOUT));
+        // Attempts while locked out aren't counted
+        let locked = retry_after(begin_attempt(&pool, &keys)).unwrap();
+        assert!(locked > 0 && locked <= BASE_LOCKOUT);
+
+        // Each failure after the lockout ends doubles the next one, up to the maximum
+        let mut conn = pool.get().unwrap();
+        conn.del::<_, ()>(&lockout_key).unwrap();
+        assert_eq!(
+            retry_after(begin_attempt(&pool, &keys)),
+            Some(BASE_LOCKOUT * 2)
+        );
+        conn.del::<_, ()>(&lockout_key).unwrap();
+        assert_eq!(
+            retry_after(begin_attempt(&pool, &keys)),
+            Some(BASE_LOCKOUT * 4)
+        );
+        conn.del::<_, ()>(&lockout_key).unwrap();
+        conn.set::<_, _, ()>(format!("login_failures:{}", name), 100)
+            .unwrap();
+        assert_eq!(retry_after(begin_attempt(&pool, &keys)), Some(MAX_LOCKOUT));
+        conn.del::<_, ()>(&[lockout_key, format!("login_failures:{}", name)])
+            .unwrap();
+    }
+
+    #[test]
+    #[ignore = "needs a redis server at REDIS_CONN"]
+    fn forgiven_attempts_dont_count() {
+        let pool = crate::models::test_redis_pool();
+        let username = uuid::Uuid::new_v4().to_string();
+        let ip = uuid::Uuid::new_v4().to_string();
+        let keys = [ThrottleKey::Username(&username), ThrottleKey::Ip(&ip)];
+        for _ in 0..keys[0].max_failures() * 2 {
+            assert!(begin_attempt(&pool, &keys).is_ok());
+            assert!(forgive_attempt(&pool, &keys).is_ok());
+        }
+        // The username locks out the attempt, but the IP allows more failures on its own
+        for _ in 0..keys[0].max_failures() {
+            assert!(begin_attempt(&pool, &keys).is_ok());
+        }
+        assert_eq!(retry_after(begin_attempt(&pool, &keys)), Some(BASE_LOCKOUT));
+        let ip_only = [ThrottleKey::Ip(&ip)];
+        assert!(begin_attempt(&pool, &ip_only).is_ok());
+    }
+}
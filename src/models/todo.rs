@@ -1,15 +1,55 @@
+use super::{db_error, now};
+use crate::errors::{resource, Error};
 use serde::{Deserialize, Serialize};
-use sqlx::FromRow;
+use sqlx::{Done, FromRow};
 
-#[derive(Serialize, Deserialize)]
+pub type Pool = sqlx::PgPool;
+
+#[derive(Deserialize)]
+/// Todo creation request body, the todo goes in the category from the route
 pub struct TodoInsert {
-    pub user_id: uuid::Uuid,
-    pub cat_id: uuid::Uuid,
     pub title: String,
     pub description: String,
 }
 
+#[derive(Deserialize)]
+/// Todo update request body, fields that are left out aren't changed
+pub struct TodoUpdate {
+    pub title: Option<String>,
+    pub description: Option<String>,
+}
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Debug, Default)]
+#[serde(rename_all = "lowercase")]
+/// Which todos a listing includes, based on whether they're completed
+pub enum TodoFilter {
+    #[default]
+    None,
+    Completed,
+    Incomplete,
+}
+
+impl TodoFilter {
+    /// The value of "completed" that todos need to have to be included, `None` for every todo
+    pub fn completed(self) -> Option<bool> {
+        match self {
+            TodoFilter::None => None,
+            TodoFilter::Completed => Some(true),
+            TodoFilter::Incomplete => Some(false),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+/// Query parameters for listing todos
+pub struct TodoQuery {
+    #[serde(default)]
+    pub filter: TodoFilter,
+    pub limit: Option<i64>,
+}
+
 #[derive(Serialize, FromRow)]
+/// A row of the "todos" table
 pub struct Todo {
     pub id: uuid::Uuid,
     pub user_id: uuid::Uuid,
@@ -17,6 +57,122 @@ pub struct Todo {
     pub title: String,
     pub description: String,
     pub completed: bool,
-    pub created_at: u64,
-    pub updated_at: u64,
+    pub created_at: i64,
+    pub updated_at: i64,
+}
+
+impl Todo {
+    /// Takes in a (validated) todo creation request body and creates the todo in the user's
+    /// category with an id of `cat_id`, returning an `Error` if the category isn't theirs
+    pub async fn insert(
+        pool: &Pool,
+        user_id: uuid::Uuid,
+        cat_id: uuid::Uuid,
+        ins: TodoInsert,
+    ) -> Result<Self, Error> {
+        // Only inserts a row if the category belongs to the user
+        sqlx::query_as!(
+            Todo,
+            "INSERT INTO todos (user_id, cat_id, title, description, completed, created_at,
+            updated_at)
+            SELECT user_id, id, $3, $4, FALSE, $5, $5 FROM categories WHERE id=$2 AND user_id=$1
+            RETURNING id, user_id, cat_id, title, description, completed, created_at, updated_at",
+            user_id,
+            cat_id,
+            ins.title,
+            ins.description,
+            now(),
+        )
+        .fetch_optional(pool)
+        .await
+        .map_err(db_error)?
+        .ok_or_else(|| resource::ErrorVariants::CategoryNotFound.to_error())
+    }
+    /// Get the user's todo with an id of `id` (in the category with an id of `cat_id`) or return
+    /// `Error`
+    pub async fn get(
+        pool: &Pool,
+        user_id: uuid::Uuid,
+        cat_id: uuid::Uuid,
+        id: uuid::Uuid,
+    ) -> Result<Self, Error> {
+        sqlx::query_as!(
+            Todo,
+            "SELECT id, user_id, cat_id, title, description, completed, created_at, updated_at
+            FROM todos WHERE id=$1 AND cat_id=$2 AND user_id=$3",
+            id,
+            cat_id,
+            user_id,
+        )
+        .fetch_optional(pool)
+        .await
+        .map_err(db_error)?
+        .ok_or_else(|| resource::ErrorVariants::TodoNotFound.to_error())
+    }
+    /// Takes in a (validated) todo update request body and applies it to the user's todo,
+    /// returning the updated todo
+    pub async fn update(
+        pool: &Pool,
+        user_id: uuid::Uuid,
+        cat_id: uuid::Uuid,
+        id: uuid::Uuid,
+        upd: TodoUpdate,
+    ) -> Result<Self, Error> {
+        sqlx::query_as!(
+            Todo,
+            "UPDATE todos SET title=COALESCE($1, title), description=COALESCE($2, description)
+            WHERE id=$3 AND cat_id=$4 AND user_id=$5
+            RETURNING id, user_id, cat_id, title, description, completed, created_at, updated_at",
+            upd.title,
+            upd.description,
+            id,
+            cat_id,
+            user_id,
+        )
+        .fetch_optional(pool)
+        .await
+        .map_err(db_error)?
+        .ok_or_else(|| resource::ErrorVariants::TodoNotFound.to_error())
+    }
+    /// Flip whether the user's todo is completed, returning the updated todo
+    pub async fn toggle(
+        pool: &Pool,
+        user_id: uuid::Uuid,
+        cat_id: uuid::Uuid,
+        id: uuid::Uuid,
+    ) -> Result<Self, Error> {
+        sqlx::query_as!(
+            Todo,
+            "UPDATE todos SET completed=NOT completed WHERE id=$1 AND cat_id=$2 AND user_id=$3
+            RETURNING id, user_id, cat_id, title, description, completed, created_at, updated_at",
+            id,
+            cat_id,
+            user_id,
+        )
+        .fetch_optional(pool)
+        .await
+        .map_err(db_error)?
+        .ok_or_else(|| resource::ErrorVariants::TodoNotFound.to_error())
+    }
+    /// Delete the user's todo with an id of `id`
+    pub async fn delete(
+        pool: &Pool,
+        user_id: uuid::Uuid,
+        cat_id: uuid::Uuid,
+        id: uuid::Uuid,
+    ) -> Result<(), Error> {
+        let done = sqlx::query!(
+            "DELETE FROM todos WHERE id=$1 AND cat_id=$2 AND user_id=$3",
+            id,
+            cat_id,
+            user_id
+        )
+        .execute(pool)
+        .await
+        .map_err(db_error)?;
+        if done.rows_affected() == 0 {
+            return Err(resource::ErrorVariants::TodoNotFound.to_error());
+        }
+        Ok(())
+    }
 }
@@ -0,0 +1,331 @@
+use super::{
+    db_error,
+    session::{store_error, RedisPool},
+};
+use crate::errors::{auth, internal_server, Error};
+use hmac::{Hmac, Mac, NewMac};
+use lazy_static::lazy_static;
+use rand::prelude::*;
+use redis::Commands;
+use serde::{Deserialize, Serialize};
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+use std::{
+    env,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+pub type Pool = sqlx::PgPool;
+
+/// Length of a TOTP time step in seconds
+const STEP: u64 = 30;
+/// Number of digits in a TOTP code
+const DIGITS: u32 = 6;
+/// Number of time steps either side of the current one that are accepted (to allow for clock
+/// drift)
+const SKEW: i64 = 1;
+/// Number of recovery codes a user is given
+const RECOVERY_CODE_COUNT: usize = 10;
+/// Login challenge life in seconds
+const CHALLENGE_LIFE: usize = 5 * 60;
+/// Number of incorrect codes a login challenge can take before it is thrown away
+const CHALLENGE_ATTEMPTS: u64 = 5;
+
+lazy_static! {
+    /// Issuer shown in authenticator apps
+    static ref TOTP_ISSUER: String = env::var("TOTP_ISSUER").unwrap_or_else(|_| "TodoAPI".to_string());
+}
+
+const BASE32: base32::Alphabet = base32::Alphabet::RFC4648 { padding: false };
+
+#[derive(Deserialize)]
+/// Request body containing a TOTP or recovery code
+pub struct TwoFactorCode {
+    pub code: String,
+}
+
+#[derive(Deserialize)]
+/// Request body for disabling two-factor authentication, accounts without a password (created
+/// through an external identity provider) can leave `password` out
+pub struct TwoFactorDisable {
+    pub password: Option<String>,
+    pub code: String,
+}
+
+#[derive(Deserialize)]
+/// Request body for completing a login with a TOTP or recovery code
+pub struct TwoFactorLogin {
+    pub challenge: String,
+    pub code: String,
+}
+
+#[derive(Serialize)]
+/// Response body when starting two-factor enrollment
+pub struct TwoFactorEnrollment {
+    pub secret: String,
+    pub otpauth_uri: String,
+}
+
+#[derive(Serialize)]
+/// Response body containing freshly generated recovery codes (the only time they are ever shown)
+pub struct RecoveryCodes {
+    pub recovery_codes: Vec<String>,
+}
+
+#[derive(Serialize)]
+/// Response body when a correct password is given but a second factor is still needed
+pub struct TwoFactorChallenge {
+    pub two_factor_required: bool,
+    pub challenge: String,
+}
+
+/// Generate a random base32 encoded TOTP secret
+pub fn generate_secret() -> String {
+    let mut bytes: [u8; 20] = [0; 20];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    base32::encode(BASE32, &bytes)
+}
+
+/// Build the `otpauth://` URI (usually shown as a QR code) for adding `secret` to an
+/// authenticator app
+pub fn otpauth_uri(secret: &str, username: &str) -> String {
+    let mut uri = url::Url::parse("otpauth://totp/").unwrap();
+    uri.set_path(&format!("{}:{}", *TOTP_ISSUER, username));
+    uri.query_pairs_mut()
+        .append_pair("secret", secret)
+        .append_pair("issuer", &TOTP_ISSUER)
+        .append_pair("algorithm", "SHA1")
+        .append_pair("digits", &DIGITS.to_string())
+        .append_pair("period", &STEP.to_string());
+    uri.to_string()
+}
+
+/// Compute the RFC 6238 code for `key` at time step `step`
+fn code_at(key: &[u8], step: u64) -> u32 {
+    let mut mac = Hmac::<Sha1>::new_varkey(key).expect("HMAC accepts keys of any length");
+    mac.update(&step.to_be_bytes());
+    let hash = mac.finalize().into_bytes();
+    // Dynamic truncation (RFC 4226 section 5.3)
+    let offset = (hash[hash.len() - 1] & 0xf) as usize;
+    let binary = u32::from_be_bytes([
+        hash[offset] & 0x7f,
+        hash[offset + 1],
+        hash[offset + 2],
+        hash[offset + 3],
+    ]);
+    binary % 10u32.pow(DIGITS)
+}
+
+/// Check a TOTP `code` against `secret`, each code is only accepted once per user (codes are
+/// remembered in redis for as long as they are valid)
+pub fn verify_code(
+    conn: &RedisPool,
+    id: uuid::Uuid,
+    secret: &str,
+    code: &str,
+) -> Result<bool, Error> {
+    let key = base32::decode(BASE32, secret).ok_or_else(|| {
+        eprintln!("Invalid TOTP secret stored for user {}", id);
+        internal_server::ErrorVariants::AuthError.to_error()
+    })?;
+    let code: u32 = match code.trim().parse() {
+        Ok(c) if code.trim().len() == DIGITS as usize => c,
+        _ => return Ok(false),
+    };
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+        / STEP;
+    let step = (-SKEW..=SKEW)
+        .map(|offset| (now as i64 + offset) as u64)
+        .find(|step| code_at(&key, *step) == code);
+    let step = match step {
+        Some(s) => s,
+        None => return Ok(false),
+    };
+    // `SET NX` only succeeds for the first use of the code
+    let mut conn = conn.get().map_err(store_error)?;
+    let first_use: bool = redis::cmd("SET")
+        .arg(format!("totp_used:{}:{}", id, step))
+        .arg(1)
+        .arg("NX")
+        .arg("EX")
+        .arg(STEP * (2 * SKEW as u64 + 1))
+        .query::<Option<String>>(&mut *conn)
+        .map_err(store_error)?
+        .is_some();
+    Ok(first_use)
+}
+
+/// Hash a recovery code (they are random enough that a fast hash is fine)
+fn hash_recovery_code(code: &str) -> String {
+    let normalised = code.trim().replace('-', "").to_uppercase();
+    format!("{:x}", Sha256::digest(normalised.as_bytes()))
+}
+
+/// A user's single use recovery codes, only their hashes are stored
+pub struct RecoveryCode;
+
+impl RecoveryCode {
+    /// Replace all of a user's recovery codes with new ones, returning the new codes
+    pub async fn regenerate(pool: &Pool, user_id: uuid::Uuid) -> Result<Vec<String>, Error> {
+        let codes: Vec<String> = (0..RECOVERY_CODE_COUNT)
+            .map(|_| {
+                let mut bytes: [u8; 5] = [0; 5];
+                rand::thread_rng().fill_bytes(&mut bytes);
+                // 8 base32 characters, split in half to make them easier to copy out
+                let code = base32::encode(BASE32, &bytes);
+                format!("{}-{}", &code[..4], &code[4..])
+            })
+            .collect();
+        let mut tx = pool.begin().await.map_err(db_error)?;
+        sqlx::query!("DELETE FROM recovery_codes WHERE user_id=$1", user_id)
+            .execute(&mut tx)
+            .await
+            .map_err(db_error)?;
+        for code in &codes {
+            sqlx::query!(
+                "INSERT INTO recovery_codes (user_id, code_hash) VALUES ($1, $2)",
+                user_id,
+                hash_recovery_code(code),
+            )
+            .execute(&mut tx)
+            .await
+            .map_err(db_error)?;
+        }
+        tx.commit().await.map_err(db_error)?;
+        Ok(codes)
+    }
+
+    /// Use up one of a user's recovery codes, returning whether it was valid
+    pub async fn consume(pool: &Pool, user_id: uuid::Uuid, code: &str) -> Result<bool, Error> {
+        sqlx::query!(
+            "DELETE FROM recovery_codes WHERE user_id=$1 AND code_hash=$2 RETURNING id",
+            user_id,
+            hash_recovery_code(code),
+        )
+        .fetch_optional(pool)
+        .await
+        .map(|row| row.is_some())
+        .map_err(db_error)
+    }
+
+    /// Delete all of a user's recovery codes
+    pub async fn delete_all(pool: &Pool, user_id: uuid::Uuid) -> Result<(), Error> {
+        sqlx::query!("DELETE FROM recovery_codes WHERE user_id=$1", user_id)
+            .execute(pool)
+            .await
+            .map(|_| ())
+            .map_err(db_error)
+    }
+}
+
+/// A short-lived token handed out after a correct password, which has to be completed with a
+/// second factor before a session is created
+pub struct LoginChallenge;
+
+impl LoginChallenge {
+    /// Issue a login challenge for the user with an id of `id`
+    pub fn issue(conn: &RedisPool, id: uuid::Uuid) -> Result<String, Error> {
+        let mut bytes: [u8; 32] = [0; 32];
+        rand::thread_rng().fill_bytes(&mut bytes);
+        let challenge = base64::encode_config(bytes, base64::URL_SAFE_NO_PAD);
+        let mut conn = conn.get().map_err(store_error)?;
+        conn.set_ex::<_, _, ()>(
+            format!("login_challenge:{}", challenge),
+            id.to_string(),
+            CHALLENGE_LIFE,
+        )
+        .map_err(store_error)?;
+        Ok(challenge)
+    }
+
+    /// Get the id of the user that a challenge was issued to
+    pub fn get(conn: &RedisPool, challenge: &str) -> Result<uuid::Uuid, Error> {
+        let mut conn = conn.get().map_err(store_error)?;
+        let id: Option<String> = conn
+            .get(format!("login_challenge:{}", challenge))
+            .map_err(store_error)?;
+        id.and_then(|id| uuid::Uuid::parse_str(&id).ok())
+            .ok_or_else(|| auth::ErrorVariants::InvalidChallenge.to_error())
+    }
+
+    /// Count an incorrect code against a challenge, throwing the challenge away once it has had
+    /// too many
+    pub fn record_failure(conn: &RedisPool, challenge: &str) -> Result<(), Error> {
+        let mut conn = conn.get().map_err(store_error)?;
+        let attempts_key = format!("login_challenge_attempts:{}", challenge);
+        let (attempts,): (u64,) = redis::pipe()
+            .atomic()
+            .incr(&attempts_key, 1)
+            .expire(&attempts_key, CHALLENGE_LIFE)
+            .ignore()
+            .query(&mut *conn)
+            .map_err(store_error)?;
+        if attempts >= CHALLENGE_ATTEMPTS {
+            Self::consume(&mut conn, challenge)?;
+        }
+        Ok(())
+    }
+
+    /// Use up a challenge (once it has been completed), returning whether it still existed
+    pub fn complete(conn: &RedisPool, challenge: &str) -> Result<bool, Error> {
+        let mut conn = conn.get().map_err(store_error)?;
+        Self::consume(&mut conn, challenge)
+    }
+
+    fn consume(
+        conn: &mut r2d2::PooledConnection<redis::Client>,
+        challenge: &str,
+    ) -> Result<bool, Error> {
+        let deleted: usize = conn
+            .del(&[
+                format!("login_challenge:{}", challenge),
+                format!("login_challenge_attempts:{}", challenge),
+            ])
+            .map_err(store_error)?;
+        Ok(deleted > 0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn codes_match_the_rfc_6238_test_vectors() {
+        // The SHA1 vectors from RFC 6238 appendix B, cut down to 6 digits
+        let key = b"12345678901234567890";
+        for (time, code) in [
+            (59, 287082),
+            (1111111109, 81804),
+            (1111111111, 50471),
+            (1234567890, 5924),
+            (2000000000, 279037),
+            (20000000000, 353130),
+        ] {
+            assert_eq!(code_at(key, time / STEP), code, "time {}", time);
+        }
+    }
+
+    #[test]
+    #[ignore = "needs a redis server at REDIS_CONN"]
+    fn codes_are_only_accepted_once() {
+        let pool = super::super::test_redis_pool();
+        let secret = generate_secret();
+        let key = base32::decode(BASE32, &secret).unwrap();
+        let step = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+            / STEP;
+        let code = format!("{:06}", code_at(&key, step));
+        let id = uuid::Uuid::new_v4();
+        assert!(verify_code(&pool, id, &secret, &code).unwrap());
+        assert!(!verify_code(&pool, id, &secret, &code).unwrap());
+        // Each user's used codes are tracked separately
+        assert!(verify_code(&pool, uuid::Uuid::new_v4(), &secret, &code).unwrap());
+        assert!(!verify_code(&pool, id, &secret, "12345").unwrap());
+    }
+}
@@ -1,17 +1,14 @@
-use crate::errors::{auth, internal_server, Error};
+use super::access_token::Scope;
+use super::{db_error, now, password::hash_password, signing};
+use crate::errors::{auth, internal_server, resource, Error};
 use anyhow::Result;
-use argon2::{self, Config};
-use jsonwebtoken;
 use lazy_static::lazy_static;
-use rand::prelude::*;
-use redis::{Commands, RedisError};
 use serde::{Deserialize, Serialize};
-use sqlx::FromRow;
+use sqlx::{postgres::PgDatabaseError, Done, FromRow};
 use std::{
     env,
     time::{SystemTime, UNIX_EPOCH},
 };
-use woothee::parser::Parser;
 
 pub type Pool = sqlx::PgPool;
 
@@ -25,13 +22,48 @@ pub struct UserInsert {
 }
 
 #[derive(Deserialize)]
-/// User login request body
+/// User login request body, `identifier` can be either a username or an email
 pub struct UserLogin {
-    pub username: String,
-    pub email: String,
+    pub identifier: String,
     pub password: String,
 }
 
+impl UserLogin {
+    /// Whether `identifier` should be treated as an email rather than a username (usernames can't
+    /// contain an "@" so there's no ambiguity)
+    pub fn is_email(&self) -> bool {
+        self.identifier.contains('@')
+    }
+}
+
+#[derive(Deserialize)]
+/// Profile update request body, fields that are left out aren't changed
+pub struct UserUpdate {
+    pub displayname: Option<String>,
+    pub username: Option<String>,
+    pub bio: Option<String>,
+}
+
+#[derive(Deserialize)]
+/// Account deletion request body, the password can only be left out by users that don't have one
+/// (i.e. they only sign in through an external identity provider)
+pub struct AccountDeletion {
+    pub password: Option<String>,
+}
+
+#[derive(Serialize)]
+/// Response body for an account deletion that was scheduled rather than carried out immediately
+pub struct DeletionScheduled {
+    pub deletion_scheduled_at: i64,
+}
+
+#[derive(Deserialize)]
+/// Password change request body
+pub struct PasswordChange {
+    pub current_password: String,
+    pub new_password: String,
+}
+
 #[derive(Serialize, FromRow)]
 /// The full representation for a user (should never be sent to client, use `UserSafe` instead)
 pub struct User {
@@ -39,17 +71,84 @@ pub struct User {
     pub displayname: String,
     pub username: String,
     pub email: String,
-    pub password: String,
+    pub email_verified: bool,
+    /// Argon2 hash of the user's password, `None` for accounts created through an external
+    /// identity provider
+    pub password: Option<String>,
+    /// Base32 encoded TOTP secret, set once the user starts enrolling in two-factor
+    /// authentication
+    pub totp_secret: Option<String>,
+    pub totp_enabled: bool,
+    /// When the account will be deleted, if the user has asked for it to be
+    pub deletion_scheduled_at: Option<i64>,
+    /// Either "user" or "admin"
+    pub role: String,
+    /// Disabled accounts can't log in and their access tokens are rejected
+    pub disabled: bool,
+    /// Set when an admin has cleared the user's password, until they set a new one
+    pub password_reset_required: bool,
     pub created_at: i64,
 }
 
+#[derive(Clone, Copy, PartialEq, Debug, Default, Serialize)]
+#[serde(rename_all = "lowercase")]
+/// What a user is allowed to do, admins can manage other users
+pub enum Role {
+    #[default]
+    User,
+    Admin,
+}
+
+impl Role {
+    /// Parse a role from its name in the "users" table, anything unknown is an ordinary user
+    pub fn parse(role: &str) -> Self {
+        match role {
+            "admin" => Role::Admin,
+            _ => Role::User,
+        }
+    }
+}
+
+/// The parts of a user's account that are checked on every request, since they can change after
+/// an access token has been issued
+pub struct UserStatus {
+    pub role: Role,
+    pub disabled: bool,
+    pub email_verified: bool,
+}
+
 lazy_static! {
-    /// Secret for password hashing
-    static ref SECRET: String = std::env::var("SECRET").expect("SECRET env var unset");
     /// JWT secret to generate the signature for a token
-    static ref JWT_SECRET: String = env::var("JWT_SECRET").expect("JWT_SECRET env var unset");
-    /// User Agent parser
-    static ref UA_PARSER: Parser = Parser::new();
+    pub(super) static ref JWT_SECRET: String = env::var("JWT_SECRET").expect("JWT_SECRET env var unset");
+}
+
+/// Turn a unique violation on the username or email column into the matching conflict `Error`,
+/// anything else is a DBError
+fn taken_error(e: sqlx::Error) -> Error {
+    match &e {
+        sqlx::Error::Database(db) if db.code().as_deref() == Some("23505") => {
+            match db.downcast_ref::<PgDatabaseError>().constraint() {
+                Some("users_email_key") => auth::ErrorVariants::EmailTaken.to_error(),
+                _ => auth::ErrorVariants::UsernameTaken.to_error(),
+            }
+        }
+        _ => db_error(e),
+    }
+}
+
+/// How many usernames `User::insert_external` tries before giving up
+const USERNAME_ATTEMPTS: usize = 10;
+
+/// Cut `s` down to at most `max` bytes without splitting a character
+fn truncate(mut s: String, max: usize) -> String {
+    if s.len() > max {
+        let end = (0..=max)
+            .rev()
+            .find(|i| s.is_char_boundary(*i))
+            .unwrap_or(0);
+        s.truncate(end);
+    }
+    s
 }
 
 impl User {
@@ -57,19 +156,14 @@ impl User {
     /// returning the user row (minus the password) or a DBError
     pub async fn insert(pool: &Pool, ins: UserInsert) -> Result<UserSafe, Error> {
         // Hash password
-        let salt = SECRET.as_bytes();
-        let config = Config::default();
-        let hash = argon2::hash_encoded(ins.password.as_bytes(), salt, &config).unwrap();
+        let hash = hash_password(&ins.password)?;
         // Generate timestamp
-        let created_at = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .unwrap()
-            .as_secs() as i64;
+        let created_at = now();
         // Insert user into DB
         sqlx::query_as!(
             UserSafe,
             "INSERT INTO users (displayname, username, email, password, created_at)
-            VALUES ($1, $2, $3, $4, $5) RETURNING id, displayname, username, email, created_at",
+            VALUES ($1, $2, $3, $4, $5) RETURNING id, displayname, username, email, email_verified, created_at",
             ins.displayname,
             ins.username,
             ins.email,
@@ -78,17 +172,72 @@ impl User {
         )
         .fetch_one(pool)
         .await
-        // The actual db error is only necessary to us and not the client so an Error of type DBError is sent back
-        .map_err(|e| {
-            eprintln!("Database Error: {}", e);
-            internal_server::ErrorVariants::DBError.to_error()
-        })
+        .map_err(taken_error)
+    }
+    /// Inserts a user that signed in through an external identity provider (so has no password),
+    /// the username is based on `username` but made unique if it's already taken and the
+    /// displayname falls back to the username if it's missing or too short
+    pub async fn insert_external(
+        pool: &Pool,
+        displayname: Option<&str>,
+        username: &str,
+        email: &str,
+        email_verified: bool,
+    ) -> Result<User, Error> {
+        // Keep the names within the same rules as registration (3 to 128 bytes, no "@" in the
+        // username), leaving room for the suffix that makes the username unique
+        let mut base: String = truncate(
+            username
+                .chars()
+                .filter(|c| c.is_alphanumeric() || "._-".contains(*c))
+                .collect(),
+            120,
+        );
+        while base.len() < 3 {
+            base.push('_');
+        }
+        let displayname = displayname
+            .map(|name| truncate(name.trim().to_string(), 128))
+            .filter(|name| name.len() >= 3)
+            .unwrap_or_else(|| base.clone());
+        let created_at = now();
+        let mut username = base.clone();
+        // Insert first and only pick another username when the insert hits the unique constraint,
+        // checking beforehand would race with other sign ups taking the same name
+        for _ in 0..USERNAME_ATTEMPTS {
+            match sqlx::query_as!(
+                User,
+                "INSERT INTO users (displayname, username, email, email_verified, created_at)
+                VALUES ($1, $2, $3, $4, $5) RETURNING id, displayname, username, email,
+                email_verified, password, totp_secret, totp_enabled, deletion_scheduled_at, role,
+                disabled, password_reset_required, created_at",
+                displayname,
+                username,
+                email,
+                email_verified,
+                created_at,
+            )
+            .fetch_one(pool)
+            .await
+            {
+                Err(sqlx::Error::Database(db))
+                    if db.downcast_ref::<PgDatabaseError>().constraint()
+                        == Some("users_username_key") =>
+                {
+                    username = format!("{}-{:04}", base, rand::random::<u16>() % 10000);
+                }
+                res => return res.map_err(taken_error),
+            }
+        }
+        Err(auth::ErrorVariants::UsernameTaken.to_error())
     }
     /// Get `User` by username or return `Error`
     pub async fn get_by_username(pool: &Pool, username: String) -> Result<User, Error> {
         sqlx::query_as!(
             User,
-            "SELECT id, displayname, username, email, password, created_at FROM users
+            "SELECT id, displayname, username, email, email_verified, password, totp_secret,
+            totp_enabled, deletion_scheduled_at, role, disabled, password_reset_required, created_at
+            FROM users
             WHERE username=$1",
             username,
         )
@@ -102,6 +251,253 @@ impl User {
             }
         })
     }
+    /// Get `User` by email or return `Error`
+    pub async fn get_by_email(pool: &Pool, email: String) -> Result<User, Error> {
+        sqlx::query_as!(
+            User,
+            "SELECT id, displayname, username, email, email_verified, password, totp_secret,
+            totp_enabled, deletion_scheduled_at, role, disabled, password_reset_required, created_at
+            FROM users
+            WHERE email=$1",
+            email,
+        )
+        .fetch_one(pool)
+        .await
+        .map_err(|e| {
+            eprintln!("Database Error: {}", e);
+            match e {
+                sqlx::Error::RowNotFound => auth::ErrorVariants::EmailNotFound.to_error(),
+                _ => internal_server::ErrorVariants::AuthError.to_error(),
+            }
+        })
+    }
+    /// Mark the user's email as verified, as long as it hasn't changed since the verification
+    /// token was issued
+    pub async fn verify_email(pool: &Pool, id: uuid::Uuid, email: &str) -> Result<(), Error> {
+        let result = sqlx::query!(
+            "UPDATE users SET email_verified=TRUE WHERE id=$1 AND email=$2",
+            id,
+            email,
+        )
+        .execute(pool)
+        .await
+        .map_err(db_error)?;
+        if result.rows_affected() == 0 {
+            return Err(auth::ErrorVariants::InvalidVerificationToken.to_error());
+        }
+        Ok(())
+    }
+    /// Hash `password` (with the current hashing parameters) and replace the user's password hash
+    /// with it
+    pub async fn update_password(pool: &Pool, id: uuid::Uuid, password: &str) -> Result<(), Error> {
+        let hash = hash_password(password)?;
+        sqlx::query!(
+            "UPDATE users SET password=$1, password_reset_required=FALSE WHERE id=$2",
+            hash,
+            id
+        )
+        .execute(pool)
+        .await
+        .map(|_| ())
+        .map_err(db_error)
+    }
+    /// Set (or clear) the user's TOTP secret, two-factor authentication is switched off until it
+    /// is confirmed with `enable_totp`
+    pub async fn set_totp_secret(
+        pool: &Pool,
+        id: uuid::Uuid,
+        secret: Option<String>,
+    ) -> Result<(), Error> {
+        sqlx::query!(
+            "UPDATE users SET totp_secret=$1, totp_enabled=FALSE WHERE id=$2",
+            secret,
+            id
+        )
+        .execute(pool)
+        .await
+        .map(|_| ())
+        .map_err(db_error)
+    }
+    /// Switch on two-factor authentication for the user (once their TOTP secret is confirmed)
+    pub async fn enable_totp(pool: &Pool, id: uuid::Uuid) -> Result<(), Error> {
+        sqlx::query!(
+            "UPDATE users SET totp_enabled=TRUE WHERE id=$1 AND totp_secret IS NOT NULL",
+            id
+        )
+        .execute(pool)
+        .await
+        .map(|_| ())
+        .map_err(db_error)
+    }
+    /// Get the profile of the user with an id of `id`
+    pub async fn get_profile(pool: &Pool, id: uuid::Uuid) -> Result<UserProfile, Error> {
+        let row = sqlx::query!(
+            "SELECT id, displayname, username, email, email_verified, bio, deletion_scheduled_at,
+            created_at FROM users WHERE id=$1",
+            id
+        )
+        .fetch_one(pool)
+        .await
+        .map_err(db_error)?;
+        Ok(UserProfile {
+            user: UserSafe {
+                id: row.id,
+                displayname: row.displayname,
+                username: row.username,
+                email: row.email,
+                email_verified: row.email_verified,
+                created_at: row.created_at,
+            },
+            bio: row.bio,
+            deletion_scheduled_at: row.deletion_scheduled_at,
+        })
+    }
+    /// Takes in a (validated) profile update request body and applies it to the user, returning
+    /// the updated user or an `Error` if the new username is already taken
+    pub async fn update(pool: &Pool, id: uuid::Uuid, upd: UserUpdate) -> Result<User, Error> {
+        sqlx::query_as!(
+            User,
+            "UPDATE users SET displayname=COALESCE($1, displayname),
+            username=COALESCE($2, username), bio=COALESCE($3, bio) WHERE id=$4
+            RETURNING id, displayname, username, email, email_verified, password, totp_secret,
+            totp_enabled, deletion_scheduled_at, role, disabled, password_reset_required,
+            created_at",
+            upd.displayname,
+            upd.username,
+            upd.bio,
+            id
+        )
+        .fetch_one(pool)
+        .await
+        .map_err(taken_error)
+    }
+    /// Schedule the user's account to be deleted at `at` (unix epoch time in seconds)
+    pub async fn schedule_deletion(pool: &Pool, id: uuid::Uuid, at: i64) -> Result<(), Error> {
+        sqlx::query!(
+            "UPDATE users SET deletion_scheduled_at=$1 WHERE id=$2",
+            at,
+            id
+        )
+        .execute(pool)
+        .await
+        .map(|_| ())
+        .map_err(db_error)
+    }
+    /// Cancel a scheduled deletion, returning an `Error` if there isn't one
+    pub async fn cancel_deletion(pool: &Pool, id: uuid::Uuid) -> Result<(), Error> {
+        let done = sqlx::query!(
+            "UPDATE users SET deletion_scheduled_at=NULL
+            WHERE id=$1 AND deletion_scheduled_at IS NOT NULL",
+            id
+        )
+        .execute(pool)
+        .await
+        .map_err(db_error)?;
+        if done.rows_affected() == 0 {
+            return Err(auth::ErrorVariants::DeletionNotScheduled.to_error());
+        }
+        Ok(())
+    }
+    /// Delete the user along with everything they own (categories, todos, tokens, identities and
+    /// recovery codes are all removed by `ON DELETE CASCADE` in the same statement)
+    pub async fn delete(pool: &Pool, id: uuid::Uuid) -> Result<(), Error> {
+        sqlx::query!("DELETE FROM users WHERE id=$1", id)
+            .execute(pool)
+            .await
+            .map(|_| ())
+            .map_err(db_error)
+    }
+    /// Delete every user whose scheduled deletion is due, returning their ids
+    pub async fn delete_due(pool: &Pool) -> Result<Vec<uuid::Uuid>, Error> {
+        let now = now();
+        sqlx::query!(
+            "DELETE FROM users WHERE deletion_scheduled_at <= $1 RETURNING id",
+            now
+        )
+        .fetch_all(pool)
+        .await
+        .map(|rows| rows.into_iter().map(|r| r.id).collect())
+        .map_err(db_error)
+    }
+    /// Get the current role of the user with an id of `id`, whether their account is disabled and
+    /// whether they have verified their email, returning an `Error` if the user doesn't exist
+    /// (anymore)
+    pub async fn get_status(pool: &Pool, id: uuid::Uuid) -> Result<UserStatus, Error> {
+        sqlx::query!(
+            "SELECT role, disabled, email_verified FROM users WHERE id=$1",
+            id
+        )
+        .fetch_optional(pool)
+        .await
+        .map_err(|e| {
+            eprintln!("Database Error: {}", e);
+            internal_server::ErrorVariants::AuthError.to_error()
+        })?
+        .map(|row| UserStatus {
+            role: Role::parse(&row.role),
+            disabled: row.disabled,
+            email_verified: row.email_verified,
+        })
+        .ok_or_else(|| auth::ErrorVariants::InvalidAccessToken.to_error())
+    }
+    /// Disable or enable the user's account, returning an `Error` if the user doesn't exist
+    pub async fn set_disabled(pool: &Pool, id: uuid::Uuid, disabled: bool) -> Result<(), Error> {
+        let done = sqlx::query!("UPDATE users SET disabled=$1 WHERE id=$2", disabled, id)
+            .execute(pool)
+            .await
+            .map_err(db_error)?;
+        if done.rows_affected() == 0 {
+            return Err(resource::ErrorVariants::UserNotFound.to_error());
+        }
+        Ok(())
+    }
+    /// Remove the user's password (flagging the account so that it isn't mistaken for one that
+    /// never had a password) so that it has to be reset before they can log in with one again,
+    /// returning the user's email or an `Error` if the user doesn't exist
+    pub async fn clear_password(pool: &Pool, id: uuid::Uuid) -> Result<String, Error> {
+        sqlx::query!(
+            "UPDATE users SET password=NULL, password_reset_required=TRUE WHERE id=$1
+            RETURNING email",
+            id
+        )
+        .fetch_optional(pool)
+        .await
+        .map_err(db_error)?
+        .map(|row| row.email)
+        .ok_or_else(|| resource::ErrorVariants::UserNotFound.to_error())
+    }
+    /// Get `User` by id or return `Error`
+    pub async fn get_by_id(pool: &Pool, id: uuid::Uuid) -> Result<User, Error> {
+        sqlx::query_as!(
+            User,
+            "SELECT id, displayname, username, email, email_verified, password, totp_secret,
+            totp_enabled, deletion_scheduled_at, role, disabled, password_reset_required, created_at
+            FROM users
+            WHERE id=$1",
+            id,
+        )
+        .fetch_one(pool)
+        .await
+        .map_err(|e| {
+            eprintln!("Database Error: {}", e);
+            match e {
+                sqlx::Error::RowNotFound => resource::ErrorVariants::UserNotFound.to_error(),
+                _ => internal_server::ErrorVariants::AuthError.to_error(),
+            }
+        })
+    }
+}
+
+#[derive(Deserialize)]
+/// Request body for resending a verification email
+pub struct EmailVerificationResend {
+    pub email: String,
+}
+
+#[derive(Deserialize)]
+/// Query parameters for confirming an email
+pub struct EmailVerificationConfirm {
+    pub token: String,
 }
 
 #[derive(Serialize, FromRow)]
@@ -111,81 +507,17 @@ pub struct UserSafe {
     pub displayname: String,
     pub username: String,
     pub email: String,
+    pub email_verified: bool,
     pub created_at: i64,
 }
 
-#[derive(Serialize, Deserialize, Clone)]
-// Represents a user's session as stored in redis
-pub struct UserSession {
-    #[serde(skip_serializing, skip_deserializing)]
-    pub id: uuid::Uuid,
-    pub token: String,
-    pub os: String,
-    pub browser: String,
-    pub expiry: u64,
-}
-
-const SESSION_LIFE: u64 = 24 * 60 * 60;
-
-impl UserSession {
-    /// Construct `UserSession` from user's id, a randomly generated session/refresh token and their
-    /// useragent (as a means of rough identification) and return it
-    pub fn new(id: uuid::Uuid, useragent: &str) -> Self {
-        // Generate 48 byte long buffer of random bytes
-        let mut bytes: [u8; 48] = [0; 48];
-        let mut rng = rand::thread_rng();
-        rng.fill_bytes(&mut bytes);
-        // Convert the buffer to a base64 string
-        let token = base64::encode_config(&bytes, base64::URL_SAFE);
-        // Parse the user agent (into `Result` since useragent can be invalid)
-        let parsed = UA_PARSER.parse(useragent);
-        // Get the current epoch time and add on the session life
-        let start = SystemTime::now();
-        let expiry = start.duration_since(UNIX_EPOCH).unwrap().as_secs() + SESSION_LIFE;
-        // If the useragent is invalid, populate the os and browser fields with unknown
-        if let None = parsed {
-            return UserSession {
-                id,
-                token,
-                os: "Unknown".to_string(),
-                browser: "Unknown".to_string(),
-                expiry,
-            };
-        }
-        // If we've reached this point then `parsed` should be `Ok` so return the (ideal) session
-        // object
-        let parsed = parsed.unwrap();
-        UserSession {
-            id,
-            token,
-            os: parsed.os.to_string(),
-            browser: parsed.browser_type.to_string(),
-            expiry,
-        }
-    }
-
-    /// Save `self` to the appropriate redis session and return a session token
-    pub fn set_session(&self, conn: &r2d2::Pool<redis::Client>) -> String {
-        // Grab connection from connection pool
-        let mut conn = conn.get().unwrap();
-        // Grab the user's sessions
-        let sessions: Result<String, RedisError> = conn.get(format!("sessions:{}", self.id));
-        // If the value exists, then deserialise the JSON array from a string into
-        // `Vec<UserSession>`, else, assign an empty vector
-        let mut sessions: Vec<UserSession> = if let Ok(s) = sessions {
-            serde_json::from_str(&s).unwrap()
-        } else {
-            vec![]
-        };
-        // Push the current session onto the vector of sessions
-        sessions.push(self.clone());
-        // Serialise the vector of sessions into a string, `SET` it on the appropriate key and
-        // return a clone of the session/refresh token
-        let sessions = serde_json::to_string(&sessions).unwrap();
-        conn.set::<String, String, ()>(format!("sessions:{}", self.id), sessions)
-            .unwrap();
-        self.token.clone()
-    }
+#[derive(Serialize)]
+/// A user's profile (`UserSafe` plus the profile only fields)
+pub struct UserProfile {
+    #[serde(flatten)]
+    pub user: UserSafe,
+    pub bio: String,
+    pub deletion_scheduled_at: Option<i64>,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -195,7 +527,16 @@ pub struct UserClaims {
     pub displayname: String,
     pub username: String,
     pub email: String,
+    pub email_verified: bool,
     pub exp: usize,
+    /// Scopes of the personal access token the request was authenticated with, `None` when it was
+    /// authenticated with the "access_token" cookie (which isn't limited)
+    #[serde(skip)]
+    pub scopes: Option<Vec<Scope>>,
+    /// The user's current role, looked up when the request is authenticated rather than trusted
+    /// from the token
+    #[serde(skip)]
+    pub role: Role,
 }
 
 /// Access JWT Life in seconds
@@ -213,16 +554,92 @@ impl UserClaims {
             displayname: user.displayname,
             username: user.username,
             email: user.email,
+            email_verified: user.email_verified,
             exp: (since_the_epoch as usize) + ACCESS_TOKEN_LIFE,
+            scopes: None,
+            role: Role::parse(&user.role),
         }
     }
-    /// Converts/encodes `self` into a JWT string (to be used as the access token)
-    pub fn to_token(self) -> String {
-        jsonwebtoken::encode(
-            &jsonwebtoken::Header::default(),
-            &self,
-            &jsonwebtoken::EncodingKey::from_secret(JWT_SECRET.as_ref()),
-        )
-        .unwrap()
+    /// Returns an `Error` unless the request was authenticated with a session or a personal
+    /// access token that has the `required` scope
+    pub fn require_scope(&self, required: Scope) -> Result<(), Error> {
+        match &self.scopes {
+            Some(scopes) if !scopes.iter().any(|s| s.grants(required)) => {
+                Err(auth::ErrorVariants::InsufficientScope.to_error())
+            }
+            _ => Ok(()),
+        }
+    }
+    /// Returns an `Error` if the request was authenticated with a personal access token, for
+    /// routes that manage the account itself
+    pub fn require_session(&self) -> Result<(), Error> {
+        match self.scopes {
+            Some(_) => Err(auth::ErrorVariants::InsufficientScope.to_error()),
+            None => Ok(()),
+        }
+    }
+    /// Returns an `Error` unless the request was authenticated with an admin's session
+    pub fn require_admin(&self) -> Result<(), Error> {
+        self.require_session()?;
+        match self.role {
+            Role::Admin => Ok(()),
+            Role::User => Err(auth::ErrorVariants::AdminRequired.to_error()),
+        }
+    }
+    /// Decodes and validates (signature and expiry) an access token, returning the claims within
+    pub fn from_token(token: &str) -> Result<Self, Error> {
+        signing::verify::<UserClaims>(token).map_err(|e| match e.kind() {
+            jsonwebtoken::errors::ErrorKind::ExpiredSignature => {
+                auth::ErrorVariants::AccessTokenExpired.to_error()
+            }
+            _ => auth::ErrorVariants::InvalidAccessToken.to_error(),
+        })
+    }
+    /// Converts/encodes `self` into a JWT string (to be used as the access token), signed with
+    /// the active signing key
+    pub fn to_token(&self) -> String {
+        signing::sign(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn email() -> String {
+        format!("{}@example.com", uuid::Uuid::new_v4())
+    }
+
+    #[actix_rt::test]
+    async fn external_sign_ups_get_a_unique_username_and_a_valid_displayname() {
+        let pool = super::super::test_db_pool().await;
+        let base = format!("ext{}", &uuid::Uuid::new_v4().to_simple().to_string()[..12]);
+        let first =
+            User::insert_external(&pool, Some("  Jo "), &format!("{}!", base), &email(), true)
+                .await
+                .unwrap();
+        assert_eq!(first.username, base);
+        assert_eq!(first.displayname, base);
+        assert!(first.password.is_none());
+
+        let long_name = "é".repeat(100);
+        let second = User::insert_external(&pool, Some(&long_name), &base, &email(), false)
+            .await
+            .unwrap();
+        assert!(second.username.starts_with(&format!("{}-", base)));
+        assert_eq!(second.username.len(), base.len() + 5);
+        assert_eq!(second.displayname, "é".repeat(64));
+
+        let taken = User::insert_external(&pool, None, "someone", &first.email, true).await;
+        assert_eq!(taken.err().map(|e| e.variant()), Some("EmailTaken"));
+        User::delete(&pool, first.id).await.unwrap();
+        User::delete(&pool, second.id).await.unwrap();
+    }
+
+    #[test]
+    fn truncating_keeps_whole_characters() {
+        assert_eq!(truncate("abcdef".to_string(), 4), "abcd");
+        assert_eq!(truncate("aé".to_string(), 2), "a");
+        assert_eq!(truncate("ab".to_string(), 4), "ab");
     }
 }
@@ -0,0 +1,86 @@
+use super::{
+    session::{store_error, RedisPool},
+    user::JWT_SECRET,
+};
+use crate::errors::{auth, Error};
+use redis::Commands;
+use serde::{Deserialize, Serialize};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Verification token life in seconds
+const VERIFICATION_TOKEN_LIFE: usize = 24 * 60 * 60;
+
+/// Distinguishes verification tokens from any other JWTs signed with the same secret
+const VERIFICATION_PURPOSE: &str = "email_verification";
+
+#[derive(Serialize, Deserialize)]
+/// Claims of an email verification token
+struct VerificationClaims {
+    /// Id of the user whose email is being verified
+    sub: uuid::Uuid,
+    /// The email being verified (so that the token is useless if the email changes)
+    email: String,
+    /// Unique id of the token, it must still be in redis for the token to be accepted
+    jti: uuid::Uuid,
+    purpose: String,
+    exp: usize,
+}
+
+/// Issue a signed, single use email verification token for the user with an id of `id`
+pub fn issue_verification_token(
+    conn: &RedisPool,
+    id: uuid::Uuid,
+    email: &str,
+) -> Result<String, Error> {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs() as usize;
+    let claims = VerificationClaims {
+        sub: id,
+        email: email.to_string(),
+        jti: uuid::Uuid::new_v4(),
+        purpose: VERIFICATION_PURPOSE.to_string(),
+        exp: now + VERIFICATION_TOKEN_LIFE,
+    };
+    let mut conn = conn.get().map_err(store_error)?;
+    conn.set_ex::<_, _, ()>(
+        format!("email_verification:{}", claims.jti),
+        id.to_string(),
+        VERIFICATION_TOKEN_LIFE,
+    )
+    .map_err(store_error)?;
+    Ok(jsonwebtoken::encode(
+        &jsonwebtoken::Header::default(),
+        &claims,
+        &jsonwebtoken::EncodingKey::from_secret(JWT_SECRET.as_ref()),
+    )
+    .unwrap())
+}
+
+/// Validate an email verification token and use it up, returning the id of the user and the email
+/// that it verifies
+pub fn consume_verification_token(
+    conn: &RedisPool,
+    token: &str,
+) -> Result<(uuid::Uuid, String), Error> {
+    let claims = jsonwebtoken::decode::<VerificationClaims>(
+        token,
+        &jsonwebtoken::DecodingKey::from_secret(JWT_SECRET.as_ref()),
+        &jsonwebtoken::Validation::default(),
+    )
+    .map(|data| data.claims)
+    .map_err(|_| auth::ErrorVariants::InvalidVerificationToken.to_error())?;
+    if claims.purpose != VERIFICATION_PURPOSE {
+        return Err(auth::ErrorVariants::InvalidVerificationToken.to_error());
+    }
+    // Deleting the token's id is what uses it up, so only the first request to get here wins
+    let mut conn = conn.get().map_err(store_error)?;
+    let deleted: usize = conn
+        .del(format!("email_verification:{}", claims.jti))
+        .map_err(store_error)?;
+    if deleted == 0 {
+        return Err(auth::ErrorVariants::InvalidVerificationToken.to_error());
+    }
+    Ok((claims.sub, claims.email))
+}
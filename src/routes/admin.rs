@@ -0,0 +1,181 @@
+use super::helpers::auth::*;
+use crate::mail::MailTransport;
+use crate::models::{
+    access_token::AccessToken,
+    admin::*,
+    auth_event::{AuthEvent, AuthEventKind, AuthEventQuery},
+    session::UserSession,
+    user::{User, UserClaims},
+};
+use actix_web::{get, post, web, HttpResponse, Responder, ResponseError};
+use std::sync::Arc;
+
+#[get("")]
+/// Lists users (optionally only those matching a search query) a page at a time
+pub async fn list_users(
+    pool: web::Data<sqlx::PgPool>,
+    claims: UserClaims,
+    search: web::Query<UserSearch>,
+) -> impl Responder {
+    if let Err(e) = claims.require_admin() {
+        return e.error_response();
+    }
+    match AdminUser::search(pool.into_inner().as_ref(), search.into_inner()).await {
+        Ok(users) => HttpResponse::Ok().json(users),
+        Err(e) => e.error_response(),
+    }
+}
+
+#[get("/events")]
+/// Queries the auth events of every user, optionally filtered by user, kind and IP
+pub async fn list_events(
+    pool: web::Data<sqlx::PgPool>,
+    claims: UserClaims,
+    query: web::Query<AuthEventQuery>,
+) -> impl Responder {
+    if let Err(e) = claims.require_admin() {
+        return e.error_response();
+    }
+    match AuthEvent::query(pool.into_inner().as_ref(), query.into_inner()).await {
+        Ok(events) => HttpResponse::Ok().json(events),
+        Err(e) => e.error_response(),
+    }
+}
+
+#[get("/{user_id}")]
+/// Gets a user's details along with how many active sessions they have
+pub async fn get_user(
+    pool: web::Data<sqlx::PgPool>,
+    redis_pool: web::Data<r2d2::Pool<redis::Client>>,
+    claims: UserClaims,
+    user_id: web::Path<uuid::Uuid>,
+) -> impl Responder {
+    if let Err(e) = claims.require_admin() {
+        return e.error_response();
+    }
+    let user = match AdminUser::get(pool.into_inner().as_ref(), user_id.into_inner()).await {
+        Ok(u) => u,
+        Err(e) => return e.error_response(),
+    };
+    match UserSession::get_all(redis_pool.into_inner().as_ref(), user.user.id) {
+        Ok(sessions) => HttpResponse::Ok().json(AdminUserDetails {
+            user,
+            session_count: sessions.len(),
+        }),
+        Err(e) => e.error_response(),
+    }
+}
+
+#[post("/{user_id}/disable")]
+/// Disables a user's account, logging them out of every session
+pub async fn disable_user(
+    pool: web::Data<sqlx::PgPool>,
+    redis_pool: web::Data<r2d2::Pool<redis::Client>>,
+    req: web::HttpRequest,
+    claims: UserClaims,
+    user_id: web::Path<uuid::Uuid>,
+) -> impl Responder {
+    if let Err(e) = claims.require_admin() {
+        return e.error_response();
+    }
+    let user_id = user_id.into_inner();
+    if let Err(e) = User::set_disabled(pool.into_inner().as_ref(), user_id, true).await {
+        return e.error_response();
+    }
+    if let Err(e) = UserSession::revoke_all(redis_pool.into_inner().as_ref(), user_id) {
+        return e.error_response();
+    }
+    record_admin_event(&req, user_id, AuthEventKind::AccountDisabled, claims.id);
+    HttpResponse::Ok().json(SuccessMessage {
+        message: "Successfully disabled user",
+    })
+}
+
+#[post("/{user_id}/enable")]
+/// Enables a disabled user's account
+pub async fn enable_user(
+    pool: web::Data<sqlx::PgPool>,
+    req: web::HttpRequest,
+    claims: UserClaims,
+    user_id: web::Path<uuid::Uuid>,
+) -> impl Responder {
+    if let Err(e) = claims.require_admin() {
+        return e.error_response();
+    }
+    let user_id = user_id.into_inner();
+    match User::set_disabled(pool.into_inner().as_ref(), user_id, false).await {
+        Ok(()) => {
+            record_admin_event(&req, user_id, AuthEventKind::AccountEnabled, claims.id);
+            HttpResponse::Ok().json(SuccessMessage {
+                message: "Successfully enabled user",
+            })
+        }
+        Err(e) => e.error_response(),
+    }
+}
+
+#[post("/{user_id}/logout")]
+/// Logs a user out of every one of their sessions
+pub async fn logout_user(
+    pool: web::Data<sqlx::PgPool>,
+    redis_pool: web::Data<r2d2::Pool<redis::Client>>,
+    req: web::HttpRequest,
+    claims: UserClaims,
+    user_id: web::Path<uuid::Uuid>,
+) -> impl Responder {
+    if let Err(e) = claims.require_admin() {
+        return e.error_response();
+    }
+    // Make sure the user exists so that a typo gets a 404
+    let user = match AdminUser::get(pool.into_inner().as_ref(), user_id.into_inner()).await {
+        Ok(u) => u,
+        Err(e) => return e.error_response(),
+    };
+    match UserSession::revoke_all(redis_pool.into_inner().as_ref(), user.user.id) {
+        Ok(()) => {
+            record_admin_event(&req, user.user.id, AuthEventKind::ForcedLogout, claims.id);
+            HttpResponse::Ok().json(SuccessMessage {
+                message: "Successfully logged user out of all sessions",
+            })
+        }
+        Err(e) => e.error_response(),
+    }
+}
+
+#[post("/{user_id}/password/reset")]
+/// Forces a user to reset their password: their current password stops working, they are logged
+/// out of every session and they are emailed a password reset link
+pub async fn reset_user_password(
+    pool: web::Data<sqlx::PgPool>,
+    redis_pool: web::Data<r2d2::Pool<redis::Client>>,
+    mailer: web::Data<dyn MailTransport>,
+    req: web::HttpRequest,
+    claims: UserClaims,
+    user_id: web::Path<uuid::Uuid>,
+) -> impl Responder {
+    if let Err(e) = claims.require_admin() {
+        return e.error_response();
+    }
+    let user_id = user_id.into_inner();
+    let pool = pool.into_inner();
+    let email = match User::clear_password(pool.as_ref(), user_id).await {
+        Ok(email) => email,
+        Err(e) => return e.error_response(),
+    };
+    // Personal access tokens would otherwise outlive the password they were created with
+    if let Err(e) = AccessToken::revoke_all(pool.as_ref(), user_id).await {
+        return e.error_response();
+    }
+    let redis_pool = redis_pool.into_inner();
+    if let Err(e) = UserSession::revoke_all(redis_pool.as_ref(), user_id) {
+        return e.error_response();
+    }
+    record_admin_event(&req, user_id, AuthEventKind::PasswordResetForced, claims.id);
+    let mailer = Arc::clone(&mailer);
+    actix_web::rt::spawn(async move {
+        send_password_reset_email(mailer, redis_pool.as_ref(), user_id, email).await
+    });
+    HttpResponse::Ok().json(SuccessMessage {
+        message: "Password cleared and password reset email sent",
+    })
+}
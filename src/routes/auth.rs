@@ -1,14 +1,27 @@
-use super::helpers::auth::*;
-use crate::models::user::*;
+use super::helpers::{auth::*, net::client_ip};
+use crate::errors::{auth, ErrorCategories};
+use crate::mail::MailTransport;
+use crate::middleware::csrf::Csrf;
+use crate::models::{
+    auth_event::{AuthEvent, AuthEventKind, AuthEventQuery},
+    password_reset::*,
+    session::*,
+    throttle::{self, ThrottleKey},
+    totp::{self, LoginChallenge, RecoveryCode, TwoFactorLogin},
+    user::*,
+    verification::consume_verification_token,
+};
 use crate::validation::Validate;
-use actix_web::{cookie::Cookie, get, post, web, HttpResponse, Responder};
-use time::Duration;
+use actix_web::{delete, get, post, put, web, HttpResponse, Responder, ResponseError};
+use std::sync::Arc;
 
 #[post("/register")]
 /// User registration route
 pub async fn register(
     pool: web::Data<sqlx::PgPool>,
-    _: web::Data<r2d2::Pool<redis::Client>>,
+    redis_pool: web::Data<r2d2::Pool<redis::Client>>,
+    mailer: web::Data<dyn MailTransport>,
+    req: web::HttpRequest,
     body: web::Json<UserInsert>,
 ) -> impl Responder {
     // Validate request body
@@ -17,10 +30,20 @@ pub async fn register(
         return HttpResponse::BadRequest().json(e.to_error());
     }
     // Insert the `User` object into the database
-    HttpResponse::Ok().json(match User::insert(pool.into_inner().as_ref(), user).await {
+    let user = match User::insert(pool.into_inner().as_ref(), user).await {
         Ok(u) => u,
-        Err(err) => return HttpResponse::InternalServerError().json(err),
-    })
+        Err(e) => return e.error_response(),
+    };
+    record_event(&req, Some(user.id), AuthEventKind::Register, None);
+    // Send the user a link to verify their email with
+    send_verification_email(
+        Arc::clone(&mailer),
+        redis_pool.into_inner().as_ref(),
+        user.id,
+        user.email.clone(),
+    )
+    .await;
+    HttpResponse::Ok().json(user)
 }
 
 #[post("/login")]
@@ -36,40 +59,513 @@ pub async fn login(
     if let Some(e) = userlogin.validate() {
         return HttpResponse::BadRequest().json(e.to_error());
     }
-    // Get `User` with a username of `userlogin.username`
-    let user = User::get_by_username(pool.into_inner().as_ref(), userlogin.username).await;
+    // Get `User` with a username or email of `userlogin.identifier`
+    let redis_pool = redis_pool.into_inner();
+    let ip = client_ip(&req);
+    let mut keys: Vec<ThrottleKey> = ip.iter().map(|ip| ThrottleKey::Ip(ip)).collect();
+    let pool = pool.into_inner();
+    let user = if userlogin.is_email() {
+        User::get_by_email(pool.as_ref(), userlogin.identifier.clone()).await
+    } else {
+        User::get_by_username(pool.as_ref(), userlogin.identifier.clone()).await
+    };
+    let username = user.as_ref().map(|u| u.username.clone()).ok();
+    keys.extend(username.as_deref().map(ThrottleKey::Username));
+    // Count the attempt before checking anything, refusing it if the client or account is locked
+    // out
+    if let Err(e) = throttle::begin_attempt(redis_pool.as_ref(), &keys) {
+        return e.error_response();
+    }
     if let Err(e) = user {
-        return match e.error.kind {
-            "AuthError" => HttpResponse::NotFound().json(e),
-            "InternalServerError" => HttpResponse::InternalServerError().json(e),
-            _ => HttpResponse::InternalServerError().json(()),
-        };
+        if !matches!(e.error.body, ErrorCategories::AuthError(_)) {
+            return e.error_response();
+        }
+        record_event(
+            &req,
+            None,
+            AuthEventKind::LoginFailed,
+            Some(userlogin.identifier),
+        );
+        // Unknown usernames/emails get the same error as a wrong password, so they can't be used
+        // to find out who has an account
+        return auth::ErrorVariants::IncorrectPassword
+            .to_error()
+            .error_response();
     }
     // Check given password against password hash
     let user = user.unwrap();
-    if let Some(e) = check_password_hash(userlogin.password, &user.password) {
-        return HttpResponse::Unauthorized().json(e);
+    let outdated = match check_password_hash(&userlogin.password, user.password.as_deref()) {
+        Ok(outdated) => outdated,
+        Err(e) => {
+            if e.error.kind == "AuthError" {
+                record_event(
+                    &req,
+                    Some(user.id),
+                    AuthEventKind::LoginFailed,
+                    Some(userlogin.identifier),
+                );
+            }
+            return e.error_response();
+        }
+    };
+    // Only this attempt is taken back, earlier failures (e.g. from another client guessing the
+    // password) still count
+    if let Err(e) = throttle::forgive_attempt(redis_pool.as_ref(), &keys) {
+        return e.error_response();
+    }
+    // Transparently upgrade the hash now that we have the plaintext password, a failure here
+    // shouldn't stop the user from logging in
+    if outdated {
+        if let Err(e) = User::update_password(pool.as_ref(), user.id, &userlogin.password).await {
+            eprintln!(
+                "Failed to upgrade password hash for user {}: {}",
+                user.id, e
+            );
+        }
+    }
+    finish_login(&req, redis_pool.as_ref(), user)
+}
+
+#[post("/login/2fa")]
+/// Completes a login for a user with two-factor authentication, using the challenge from the
+/// login route and either a TOTP code or a recovery code
+pub async fn login_two_factor(
+    pool: web::Data<sqlx::PgPool>,
+    redis_pool: web::Data<r2d2::Pool<redis::Client>>,
+    req: web::HttpRequest,
+    body: web::Json<TwoFactorLogin>,
+) -> impl Responder {
+    let body = body.into_inner();
+    let redis_pool = redis_pool.into_inner();
+    let pool = pool.into_inner();
+    let id = match LoginChallenge::get(redis_pool.as_ref(), &body.challenge) {
+        Ok(id) => id,
+        Err(e) => return e.error_response(),
+    };
+    let user = match User::get_by_id(pool.as_ref(), id).await {
+        Ok(u) => u,
+        Err(e) if matches!(e.error.body, ErrorCategories::ResourceError(_)) => {
+            return auth::ErrorVariants::InvalidChallenge
+                .to_error()
+                .error_response()
+        }
+        Err(e) => return e.error_response(),
+    };
+    // Guessing the code is throttled against the same keys as guessing the password
+    let ip = client_ip(&req);
+    let mut keys: Vec<ThrottleKey> = ip.iter().map(|ip| ThrottleKey::Ip(ip)).collect();
+    keys.push(ThrottleKey::Username(&user.username));
+    if let Err(e) = throttle::begin_attempt(redis_pool.as_ref(), &keys) {
+        return e.error_response();
+    }
+    // Try the code as a TOTP code first and then as a recovery code
+    let valid = match &user.totp_secret {
+        Some(secret) if user.totp_enabled => {
+            match totp::verify_code(redis_pool.as_ref(), user.id, secret, &body.code) {
+                Ok(true) => Ok(true),
+                Ok(false) => RecoveryCode::consume(pool.as_ref(), user.id, &body.code).await,
+                Err(e) => Err(e),
+            }
+        }
+        _ => Ok(false),
+    };
+    match valid {
+        Ok(true) => (),
+        Ok(false) => {
+            record_event(&req, Some(user.id), AuthEventKind::LoginFailed, None);
+            if let Err(e) = LoginChallenge::record_failure(redis_pool.as_ref(), &body.challenge) {
+                return e.error_response();
+            }
+            return auth::ErrorVariants::InvalidTwoFactorCode
+                .to_error()
+                .error_response();
+        }
+        Err(e) => return e.error_response(),
     }
+    if let Err(e) = throttle::forgive_attempt(redis_pool.as_ref(), &keys) {
+        return e.error_response();
+    }
+    // The challenge can only be completed once
+    match LoginChallenge::complete(redis_pool.as_ref(), &body.challenge) {
+        Ok(true) => start_session(&req, redis_pool.as_ref(), user),
+        Ok(false) => auth::ErrorVariants::InvalidChallenge
+            .to_error()
+            .error_response(),
+        Err(e) => e.error_response(),
+    }
+}
 
-    let useragent = req.headers().get("User-Agent").unwrap().to_str().unwrap();
-    // Construct session (from user's id and useragent) and get the session/refresh token
-    let refresh_token =
-        UserSession::new(user.id, useragent).set_session(redis_pool.into_inner().as_ref());
-    let refresh_token_cookie = Cookie::build("refresh_token", refresh_token)
-        .path("/")
-        .max_age(Duration::days(1))
-        .finish();
-    // Generate access token string from `user` object
-    let access_token = UserClaims::from_user(user).to_token();
-    let access_token_cookie = Cookie::build("access_token", access_token)
-        .path("/")
-        .max_age(Duration::minutes(20))
-        .finish();
-    // Return `200` response with both cookies and a success message
-    HttpResponse::Ok()
-        .cookie(refresh_token_cookie)
-        .cookie(access_token_cookie)
-        .json(SuccessMessage {
-            message: "Successfully Logged in",
-        })
+#[get("/refresh")]
+/// Access token refresh route
+pub async fn refresh(
+    pool: web::Data<sqlx::PgPool>,
+    redis_pool: web::Data<r2d2::Pool<redis::Client>>,
+    req: web::HttpRequest,
+) -> impl Responder {
+    // Swap the refresh token for a new one (this also checks that the session exists and hasn't
+    // expired)
+    let refresh_token = match refresh_token(&req) {
+        Some(t) => t,
+        None => {
+            return auth::ErrorVariants::MissingRefreshToken
+                .to_error()
+                .error_response()
+        }
+    };
+    let session = match UserSession::rotate(
+        redis_pool.into_inner().as_ref(),
+        &refresh_token,
+        client_ip(&req),
+    ) {
+        Ok(Rotation::Rotated(s)) => s,
+        Ok(Rotation::Reused(user_id)) => {
+            record_event(&req, user_id, AuthEventKind::RefreshTokenReused, None);
+            return auth::ErrorVariants::RefreshTokenReused
+                .to_error()
+                .error_response();
+        }
+        Err(e) => return e.error_response(),
+    };
+    // Get the session's `User` so that the claims are up to date
+    let user = match User::get_by_id(pool.into_inner().as_ref(), session.id).await {
+        Ok(u) if u.disabled => {
+            return auth::ErrorVariants::AccountDisabled
+                .to_error()
+                .error_response()
+        }
+        Ok(u) => u,
+        // The session outlived its user
+        Err(e) if matches!(e.error.body, ErrorCategories::ResourceError(_)) => {
+            return auth::ErrorVariants::InvalidRefreshToken
+                .to_error()
+                .error_response()
+        }
+        Err(e) => return e.error_response(),
+    };
+    record_event(&req, Some(user.id), AuthEventKind::Refresh, None);
+    // Return `200` response with the new tokens (including a new access token generated from
+    // `user`)
+    session_response(&req, user, session, "Successfully refreshed access token")
+}
+
+#[get("/sessions")]
+/// Lists the user's active sessions (identified by the refresh token)
+pub async fn sessions(
+    redis_pool: web::Data<r2d2::Pool<redis::Client>>,
+    req: web::HttpRequest,
+) -> impl Responder {
+    let redis_pool = redis_pool.into_inner();
+    let current = match current_session(&req, redis_pool.as_ref()) {
+        Ok(s) => s,
+        Err(e) => return e.error_response(),
+    };
+    match UserSession::get_all(redis_pool.as_ref(), current.id) {
+        Ok(sessions) => HttpResponse::Ok().json(
+            sessions
+                .into_iter()
+                .map(|s| UserSessionInfo::from_session(s, current.session_id))
+                .collect::<Vec<_>>(),
+        ),
+        Err(e) => e.error_response(),
+    }
+}
+
+#[put("/sessions/{session_id}", wrap = "Csrf")]
+/// Names (or renames) the device of one of the user's sessions
+pub async fn rename_session(
+    redis_pool: web::Data<r2d2::Pool<redis::Client>>,
+    req: web::HttpRequest,
+    session_id: web::Path<uuid::Uuid>,
+    body: web::Json<SessionUpdate>,
+) -> impl Responder {
+    // Validate request body
+    let update = body.into_inner();
+    if let Some(e) = update.validate() {
+        return HttpResponse::BadRequest().json(e.to_error());
+    }
+    let redis_pool = redis_pool.into_inner();
+    let current = match current_session(&req, redis_pool.as_ref()) {
+        Ok(s) => s,
+        Err(e) => return e.error_response(),
+    };
+    let device_name = update.device_name.as_deref().map(str::trim);
+    match UserSession::rename(
+        redis_pool.as_ref(),
+        current.id,
+        session_id.into_inner(),
+        device_name.filter(|name| !name.is_empty()),
+    ) {
+        Ok(()) => HttpResponse::Ok().json(SuccessMessage {
+            message: "Successfully renamed session",
+        }),
+        Err(e) => e.error_response(),
+    }
+}
+
+#[delete("/sessions/{session_id}", wrap = "Csrf")]
+/// Revokes one of the user's sessions
+pub async fn revoke_session(
+    redis_pool: web::Data<r2d2::Pool<redis::Client>>,
+    req: web::HttpRequest,
+    session_id: web::Path<uuid::Uuid>,
+) -> impl Responder {
+    let redis_pool = redis_pool.into_inner();
+    let current = match current_session(&req, redis_pool.as_ref()) {
+        Ok(s) => s,
+        Err(e) => return e.error_response(),
+    };
+    let session_id = session_id.into_inner();
+    if let Err(e) = UserSession::revoke(redis_pool.as_ref(), current.id, session_id) {
+        return e.error_response();
+    }
+    record_event(&req, Some(current.id), AuthEventKind::SessionRevoked, None);
+    // If the current session was the one revoked then the client's cookies are useless
+    let mut res = HttpResponse::Ok();
+    if session_id == current.session_id {
+        remove_auth_cookies(&mut res);
+    }
+    res.json(SuccessMessage {
+        message: "Successfully revoked session",
+    })
+}
+
+#[post("/logout", wrap = "Csrf")]
+/// Logs out of the current session
+pub async fn logout(
+    redis_pool: web::Data<r2d2::Pool<redis::Client>>,
+    req: web::HttpRequest,
+) -> impl Responder {
+    let redis_pool = redis_pool.into_inner();
+    let current = match current_session(&req, redis_pool.as_ref()) {
+        Ok(s) => s,
+        Err(e) => return e.error_response(),
+    };
+    if let Err(e) = UserSession::revoke(redis_pool.as_ref(), current.id, current.session_id) {
+        return e.error_response();
+    }
+    record_event(&req, Some(current.id), AuthEventKind::SessionRevoked, None);
+    let mut res = HttpResponse::Ok();
+    remove_auth_cookies(&mut res);
+    res.json(SuccessMessage {
+        message: "Successfully logged out",
+    })
+}
+
+#[post("/logout/all", wrap = "Csrf")]
+/// Logs out of every one of the user's sessions
+pub async fn logout_all(
+    redis_pool: web::Data<r2d2::Pool<redis::Client>>,
+    req: web::HttpRequest,
+) -> impl Responder {
+    let redis_pool = redis_pool.into_inner();
+    let current = match current_session(&req, redis_pool.as_ref()) {
+        Ok(s) => s,
+        Err(e) => return e.error_response(),
+    };
+    if let Err(e) = UserSession::revoke_all(redis_pool.as_ref(), current.id) {
+        return e.error_response();
+    }
+    record_event(&req, Some(current.id), AuthEventKind::SessionRevoked, None);
+    let mut res = HttpResponse::Ok();
+    remove_auth_cookies(&mut res);
+    res.json(SuccessMessage {
+        message: "Successfully logged out of all sessions",
+    })
+}
+
+#[post("/password/change", wrap = "Csrf")]
+/// Changes the user's password (given their current one) and logs out of every other session
+pub async fn change_password(
+    pool: web::Data<sqlx::PgPool>,
+    redis_pool: web::Data<r2d2::Pool<redis::Client>>,
+    req: web::HttpRequest,
+    claims: UserClaims,
+    body: web::Json<PasswordChange>,
+) -> impl Responder {
+    if let Err(e) = claims.require_session() {
+        return e.error_response();
+    }
+    // Validate request body
+    let change = body.into_inner();
+    if let Some(e) = change.validate() {
+        return HttpResponse::BadRequest().json(e.to_error());
+    }
+    // The session that is kept alive has to belong to the same user as the access token
+    let redis_pool = redis_pool.into_inner();
+    let current = match current_session(&req, redis_pool.as_ref()) {
+        Ok(s) if s.id == claims.id => s,
+        Ok(_) => {
+            return auth::ErrorVariants::InvalidRefreshToken
+                .to_error()
+                .error_response()
+        }
+        Err(e) => return e.error_response(),
+    };
+    let pool = pool.into_inner();
+    let user = match User::get_by_id(pool.as_ref(), claims.id).await {
+        Ok(u) => u,
+        Err(e) => return e.error_response(),
+    };
+    // Guessing the current password is throttled the same way as logging in
+    let key = [ThrottleKey::Username(&user.username)];
+    if let Err(e) = throttle::begin_attempt(redis_pool.as_ref(), &key) {
+        return e.error_response();
+    }
+    if let Err(e) = check_password_hash(&change.current_password, user.password.as_deref()) {
+        if matches!(e.error.body, ErrorCategories::AuthError(_)) {
+            record_event(
+                &req,
+                Some(user.id),
+                AuthEventKind::PasswordChangeFailed,
+                None,
+            );
+        }
+        return e.error_response();
+    }
+    if let Err(e) = throttle::forgive_attempt(redis_pool.as_ref(), &key) {
+        return e.error_response();
+    }
+    // The other sessions are logged out before the password changes, so a failure can't leave
+    // them alive with the new password in place
+    if let Err(e) = UserSession::revoke_all_except(redis_pool.as_ref(), user.id, current.session_id)
+    {
+        return e.error_response();
+    }
+    if let Err(e) = User::update_password(pool.as_ref(), user.id, &change.new_password).await {
+        return e.error_response();
+    }
+    record_event(&req, Some(user.id), AuthEventKind::PasswordChanged, None);
+    HttpResponse::Ok().json(SuccessMessage {
+        message: "Password successfully changed",
+    })
+}
+
+#[get("/history")]
+/// Lists the user's own auth events (e.g. `?kind=login` for their login history), newest first
+pub async fn history(
+    pool: web::Data<sqlx::PgPool>,
+    claims: UserClaims,
+    query: web::Query<AuthEventQuery>,
+) -> impl Responder {
+    if let Err(e) = claims.require_session() {
+        return e.error_response();
+    }
+    // Users can only see their own events
+    let query = AuthEventQuery {
+        user_id: Some(claims.id),
+        ..query.into_inner()
+    };
+    match AuthEvent::query(pool.into_inner().as_ref(), query).await {
+        Ok(events) => HttpResponse::Ok().json(events),
+        Err(e) => e.error_response(),
+    }
+}
+
+#[post("/verify/resend")]
+/// Resends the verification email (the response is the same whether or not the email belongs to
+/// an unverified user, so it can't be used to find out who has an account)
+pub async fn resend_verification(
+    pool: web::Data<sqlx::PgPool>,
+    redis_pool: web::Data<r2d2::Pool<redis::Client>>,
+    mailer: web::Data<dyn MailTransport>,
+    body: web::Json<EmailVerificationResend>,
+) -> impl Responder {
+    let email = body.into_inner().email;
+    match User::get_by_email(pool.into_inner().as_ref(), email).await {
+        Ok(user) if !user.email_verified => {
+            send_verification_email(
+                Arc::clone(&mailer),
+                redis_pool.into_inner().as_ref(),
+                user.id,
+                user.email,
+            )
+            .await
+        }
+        Ok(_) => {}
+        Err(e) if e.error.kind == "AuthError" => {}
+        Err(e) => return e.error_response(),
+    }
+    HttpResponse::Ok().json(SuccessMessage {
+        message:
+            "If an unverified account with that email exists, a verification email has been sent",
+    })
+}
+
+#[get("/verify")]
+/// Email verification route (linked to in the verification email)
+pub async fn verify_email(
+    pool: web::Data<sqlx::PgPool>,
+    redis_pool: web::Data<r2d2::Pool<redis::Client>>,
+    query: web::Query<EmailVerificationConfirm>,
+) -> impl Responder {
+    let (id, email) =
+        match consume_verification_token(redis_pool.into_inner().as_ref(), &query.token) {
+            Ok(v) => v,
+            Err(e) => return e.error_response(),
+        };
+    if let Err(e) = User::verify_email(pool.into_inner().as_ref(), id, &email).await {
+        return e.error_response();
+    }
+    HttpResponse::Ok().json(SuccessMessage {
+        message: "Email successfully verified",
+    })
+}
+
+#[post("/password/reset")]
+/// Emails the user a password reset link (the response is the same whether or not the email
+/// belongs to an account, so it can't be used to find out who has an account)
+pub async fn request_password_reset(
+    pool: web::Data<sqlx::PgPool>,
+    redis_pool: web::Data<r2d2::Pool<redis::Client>>,
+    mailer: web::Data<dyn MailTransport>,
+    body: web::Json<PasswordResetRequest>,
+) -> impl Responder {
+    let email = body.into_inner().email;
+    match User::get_by_email(pool.into_inner().as_ref(), email).await {
+        // Send the email in the background so that the response time doesn't give away whether
+        // the account exists either
+        Ok(user) => {
+            let mailer = Arc::clone(&mailer);
+            let redis_pool = redis_pool.into_inner();
+            actix_web::rt::spawn(async move {
+                send_password_reset_email(mailer, redis_pool.as_ref(), user.id, user.email).await
+            });
+        }
+        Err(e) if e.error.kind == "AuthError" => {}
+        Err(e) => return e.error_response(),
+    }
+    HttpResponse::Ok().json(SuccessMessage {
+        message: "If an account with that email exists, a password reset email has been sent",
+    })
+}
+
+#[post("/password/reset/complete")]
+/// Sets a new password using a password reset token, logging the user out everywhere
+pub async fn complete_password_reset(
+    pool: web::Data<sqlx::PgPool>,
+    redis_pool: web::Data<r2d2::Pool<redis::Client>>,
+    req: web::HttpRequest,
+    body: web::Json<PasswordResetComplete>,
+) -> impl Responder {
+    // Validate request body
+    let reset = body.into_inner();
+    if let Some(e) = reset.validate() {
+        return HttpResponse::BadRequest().json(e.to_error());
+    }
+    let redis_pool = redis_pool.into_inner();
+    let id = match consume_reset_token(redis_pool.as_ref(), &reset.token) {
+        Ok(id) => id,
+        Err(e) => return e.error_response(),
+    };
+    if let Err(e) = User::update_password(pool.into_inner().as_ref(), id, &reset.password).await {
+        return e.error_response();
+    }
+    record_event(&req, Some(id), AuthEventKind::PasswordChanged, None);
+    // Whoever had access to the account before might not have been the owner
+    if let Err(e) = UserSession::revoke_all(redis_pool.as_ref(), id) {
+        return e.error_response();
+    }
+    HttpResponse::Ok().json(SuccessMessage {
+        message: "Password successfully reset",
+    })
 }
@@ -0,0 +1,119 @@
+use super::helpers::auth::SuccessMessage;
+use crate::models::{access_token::Scope, category::*, todo::TodoQuery, user::UserClaims};
+use crate::validation::Validate;
+use actix_web::{delete, get, post, put, web, HttpResponse, Responder, ResponseError};
+
+#[get("")]
+/// Lists the user's categories
+pub async fn get_all(
+    pool: web::Data<sqlx::PgPool>,
+    claims: UserClaims,
+    query: web::Query<CategoryQuery>,
+) -> impl Responder {
+    if let Err(e) = claims.require_scope(Scope::CategoriesRead) {
+        return e.error_response();
+    }
+    match Category::get_all(pool.into_inner().as_ref(), claims.id, query.limit).await {
+        Ok(categories) => HttpResponse::Ok().json(categories),
+        Err(e) => e.error_response(),
+    }
+}
+
+#[post("")]
+/// Creates a category for the user
+pub async fn create(
+    pool: web::Data<sqlx::PgPool>,
+    claims: UserClaims,
+    body: web::Json<CategoryInsert>,
+) -> impl Responder {
+    if let Err(e) = claims.require_scope(Scope::CategoriesWrite) {
+        return e.error_response();
+    }
+    // Validate request body
+    let category = body.into_inner();
+    if let Some(e) = category.validate() {
+        return HttpResponse::BadRequest().json(e.to_error());
+    }
+    match Category::insert(pool.into_inner().as_ref(), claims.id, category).await {
+        Ok(category) => HttpResponse::Ok().json(category),
+        Err(e) => e.error_response(),
+    }
+}
+
+#[get("/{cat_id}")]
+/// Gets one of the user's categories with its todos (optionally filtered) joined in, the todos are
+/// left empty for personal access tokens that can't read them
+pub async fn get(
+    pool: web::Data<sqlx::PgPool>,
+    claims: UserClaims,
+    cat_id: web::Path<uuid::Uuid>,
+    query: web::Query<TodoQuery>,
+) -> impl Responder {
+    if let Err(e) = claims.require_scope(Scope::CategoriesRead) {
+        return e.error_response();
+    }
+    let query = query.into_inner();
+    let limit = match claims.require_scope(Scope::TodosRead) {
+        Ok(()) => query.limit,
+        Err(_) => Some(0),
+    };
+    match Category::get_with_todos(
+        pool.into_inner().as_ref(),
+        claims.id,
+        cat_id.into_inner(),
+        query.filter,
+        limit,
+    )
+    .await
+    {
+        Ok(category) => HttpResponse::Ok().json(category),
+        Err(e) => e.error_response(),
+    }
+}
+
+#[put("/{cat_id}")]
+/// Updates one of the user's categories
+pub async fn update(
+    pool: web::Data<sqlx::PgPool>,
+    claims: UserClaims,
+    cat_id: web::Path<uuid::Uuid>,
+    body: web::Json<CategoryUpdate>,
+) -> impl Responder {
+    if let Err(e) = claims.require_scope(Scope::CategoriesWrite) {
+        return e.error_response();
+    }
+    // Validate request body
+    let update = body.into_inner();
+    if let Some(e) = update.validate() {
+        return HttpResponse::BadRequest().json(e.to_error());
+    }
+    match Category::update(
+        pool.into_inner().as_ref(),
+        claims.id,
+        cat_id.into_inner(),
+        update,
+    )
+    .await
+    {
+        Ok(category) => HttpResponse::Ok().json(category),
+        Err(e) => e.error_response(),
+    }
+}
+
+#[delete("/{cat_id}")]
+/// Deletes one of the user's categories along with all of its todos
+pub async fn delete(
+    pool: web::Data<sqlx::PgPool>,
+    claims: UserClaims,
+    cat_id: web::Path<uuid::Uuid>,
+) -> impl Responder {
+    if let Err(e) = claims.require_scope(Scope::CategoriesWrite) {
+        return e.error_response();
+    }
+    match Category::delete(pool.into_inner().as_ref(), claims.id, cat_id.into_inner()).await {
+        Ok(()) => HttpResponse::Ok().json(SuccessMessage {
+            message: "Successfully deleted category",
+        }),
+        Err(e) => e.error_response(),
+    }
+}
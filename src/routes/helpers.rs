@@ -1,6 +1,71 @@
 pub mod auth {
-    use crate::errors::{auth, Error};
+    use crate::errors::{auth, internal_server, Error};
+    use crate::mail::{Mail, MailTransport};
+    use crate::models::{
+        access_token::{AccessToken, TOKEN_PREFIX},
+        auth_event::{AuthEvent, AuthEventInsert, AuthEventKind},
+        oidc::STATE_LIFE,
+        password::{verify_password, PasswordMatch},
+        password_reset::issue_reset_token,
+        session::{parse_user_agent, BearerTokens, RedisPool, UserSession},
+        totp::{LoginChallenge, TwoFactorChallenge},
+        user::{User, UserClaims},
+        verification::issue_verification_token,
+    };
+    use actix_web::{
+        cookie::{Cookie, CookieBuilder, SameSite},
+        dev::{HttpResponseBuilder, Payload},
+        http::header,
+        web, FromRequest, HttpMessage, HttpRequest, HttpResponse, ResponseError,
+    };
+    use lazy_static::lazy_static;
+    use rand::prelude::*;
     use serde::Serialize;
+    use std::{env, future::Future, pin::Pin, sync::Arc};
+    use time::{Duration, OffsetDateTime};
+
+    lazy_static! {
+        /// Publicly reachable base URL of the API, used to build links in emails
+        static ref PUBLIC_URL: String = env::var("PUBLIC_URL").expect("PUBLIC_URL env var unset");
+        /// URL of the (client side) page where users reset their password, the reset token is
+        /// passed to it in the "token" query parameter
+        static ref PASSWORD_RESET_URL: String =
+            env::var("PASSWORD_RESET_URL").expect("PASSWORD_RESET_URL env var unset");
+        /// Domain the auth cookies are set for (`COOKIE_DOMAIN`), host only when unset
+        static ref COOKIE_DOMAIN: Option<String> =
+            env::var("COOKIE_DOMAIN").ok().filter(|d| !d.is_empty());
+        /// Whether the auth cookies are only sent over HTTPS (`COOKIE_SECURE`, defaults to true)
+        static ref COOKIE_SECURE: bool = env::var("COOKIE_SECURE")
+            .map(|s| s != "false" && s != "0")
+            .unwrap_or(true);
+        /// SameSite attribute of the auth cookies (`COOKIE_SAME_SITE`, one of "Strict", "Lax" or
+        /// "None", defaults to "Lax")
+        static ref COOKIE_SAME_SITE: SameSite = match env::var("COOKIE_SAME_SITE")
+            .unwrap_or_default()
+            .to_lowercase()
+            .as_str()
+        {
+            "" | "lax" => SameSite::Lax,
+            "strict" => SameSite::Strict,
+            "none" => SameSite::None,
+            other => panic!("Invalid COOKIE_SAME_SITE \"{}\"", other),
+        };
+    }
+
+    /// The refresh token is only needed by the session routes, so the browser doesn't send it
+    /// anywhere else
+    pub const REFRESH_COOKIE_PATH: &str = "/api/users/auth";
+    pub const CSRF_COOKIE: &str = "csrf_token";
+    /// Cookie binding an external sign in to the browser that started it, only the OIDC routes
+    /// need it
+    pub const OIDC_COOKIE: &str = "oidc_browser";
+    pub const OIDC_COOKIE_PATH: &str = "/api/users/auth/oidc";
+    pub const CSRF_HEADER: &str = "X-CSRF-Token";
+    /// Clients that can't handle cookies set this header to "bearer" to get the tokens back in
+    /// the response body instead
+    pub const AUTH_MODE_HEADER: &str = "X-Auth-Mode";
+    /// Where clients in bearer mode send their refresh token
+    pub const REFRESH_TOKEN_HEADER: &str = "X-Refresh-Token";
 
     #[derive(Serialize)]
     /// Represents a success message
@@ -8,12 +73,440 @@ pub mod auth {
         pub message: &'static str,
     }
 
-    // Check the password the user provided against the hash
-    pub fn check_password_hash(password: String, hash: &str) -> Option<Error> {
-        if !argon2::verify_encoded(hash, password.as_bytes()).unwrap() {
-            Some(auth::ErrorVariants::IncorrectPassword.to_error())
-        } else {
-            None
+    // Check the password the user provided against the hash, returning whether the hash is
+    // outdated (and should be replaced) or an `Error` if the password is incorrect (or the user
+    // doesn't have a password)
+    pub fn check_password_hash(password: &str, hash: Option<&str>) -> Result<bool, Error> {
+        let hash = hash.ok_or_else(|| auth::ErrorVariants::IncorrectPassword.to_error())?;
+        verify_password(password, hash).map(|m| matches!(m, PasswordMatch::Outdated))
+    }
+
+    /// Record an auth event for the client that made `req` in the background, failures are only
+    /// logged since the request shouldn't fail because of them
+    pub fn record_event(
+        req: &HttpRequest,
+        user_id: Option<uuid::Uuid>,
+        kind: AuthEventKind,
+        identifier: Option<String>,
+    ) {
+        insert_event(req, user_id, kind, identifier, None);
+    }
+
+    /// Record an auth event for an action the admin with an id of `admin_id` took on the user with
+    /// an id of `user_id`, in the same way as `record_event`
+    pub fn record_admin_event(
+        req: &HttpRequest,
+        user_id: uuid::Uuid,
+        kind: AuthEventKind,
+        admin_id: uuid::Uuid,
+    ) {
+        insert_event(req, Some(user_id), kind, None, Some(admin_id));
+    }
+
+    fn insert_event(
+        req: &HttpRequest,
+        user_id: Option<uuid::Uuid>,
+        kind: AuthEventKind,
+        identifier: Option<String>,
+        actor_id: Option<uuid::Uuid>,
+    ) {
+        let pool = match req.app_data::<web::Data<sqlx::PgPool>>() {
+            Some(pool) => pool.clone(),
+            None => return,
+        };
+        let useragent = req
+            .headers()
+            .get("User-Agent")
+            .and_then(|ua| ua.to_str().ok())
+            .unwrap_or_default();
+        let (os, browser) = parse_user_agent(useragent);
+        let event = AuthEventInsert {
+            user_id,
+            kind,
+            identifier,
+            ip: super::net::client_ip(req),
+            os,
+            browser,
+            actor_id,
+        };
+        actix_web::rt::spawn(async move {
+            if let Err(e) = AuthEvent::insert(pool.as_ref(), event).await {
+                eprintln!("Failed to record auth event: {}", e);
+            }
+        });
+    }
+
+    /// Whether the client asked for the tokens in the response body rather than in cookies
+    pub fn bearer_mode(req: &HttpRequest) -> bool {
+        req.headers()
+            .get(AUTH_MODE_HEADER)
+            .and_then(|h| h.to_str().ok())
+            .is_some_and(|mode| mode.eq_ignore_ascii_case("bearer"))
+    }
+
+    /// Get the request's refresh token, from the "refresh_token" cookie or else (for clients in
+    /// bearer mode) the "X-Refresh-Token" header
+    pub fn refresh_token(req: &HttpRequest) -> Option<String> {
+        req.cookie("refresh_token")
+            .map(|c| c.value().to_string())
+            .or_else(|| {
+                req.headers()
+                    .get(REFRESH_TOKEN_HEADER)
+                    .and_then(|h| h.to_str().ok())
+                    .map(|t| t.trim().to_string())
+            })
+            .filter(|t| !t.is_empty())
+    }
+
+    /// Get the (unexpired) session that the request's refresh token belongs to
+    pub fn current_session(
+        req: &HttpRequest,
+        redis_pool: &RedisPool,
+    ) -> Result<UserSession, Error> {
+        let refresh_token = refresh_token(req)
+            .ok_or_else(|| auth::ErrorVariants::MissingRefreshToken.to_error())?;
+        UserSession::get_by_token(redis_pool, &refresh_token)
+    }
+
+    /// Start building a cookie with the configured domain, secure and same-site attributes
+    fn auth_cookie(
+        name: &'static str,
+        value: String,
+        path: &'static str,
+    ) -> CookieBuilder<'static> {
+        let mut cookie = Cookie::build(name, value)
+            .path(path)
+            .secure(*COOKIE_SECURE)
+            .same_site(*COOKIE_SAME_SITE);
+        if let Some(domain) = COOKIE_DOMAIN.as_deref() {
+            cookie = cookie.domain(domain);
+        }
+        cookie
+    }
+
+    /// Generate an access token from `user` and put it in an "access_token" cookie
+    pub fn access_token_cookie(user: User) -> Cookie<'static> {
+        auth_cookie("access_token", UserClaims::from_user(user).to_token(), "/")
+            .http_only(true)
+            .max_age(Duration::minutes(20))
+            .finish()
+    }
+
+    /// Put a session's refresh token in a "refresh_token" cookie that lasts as long as `max_age`
+    pub fn refresh_token_cookie(token: String, max_age: Duration) -> Cookie<'static> {
+        auth_cookie("refresh_token", token, REFRESH_COOKIE_PATH)
+            .http_only(true)
+            .max_age(max_age)
+            .finish()
+    }
+
+    /// Generate a new CSRF token and put it in a "csrf_token" cookie, which (unlike the others)
+    /// client side scripts can read so that they can echo it back in the "X-CSRF-Token" header
+    pub fn csrf_cookie(max_age: Duration) -> Cookie<'static> {
+        let mut bytes = [0; 32];
+        rand::thread_rng().fill_bytes(&mut bytes);
+        auth_cookie(
+            CSRF_COOKIE,
+            base64::encode_config(bytes, base64::URL_SAFE_NO_PAD),
+            "/",
+        )
+        .max_age(max_age)
+        .finish()
+    }
+
+    /// Respond with a new access token for `user` and the session's refresh token, either as JSON
+    /// (in bearer mode) or in cookies along with a new CSRF token
+    pub fn session_response(
+        req: &HttpRequest,
+        user: User,
+        session: UserSession,
+        message: &'static str,
+    ) -> HttpResponse {
+        if bearer_mode(req) {
+            let claims = UserClaims::from_user(user);
+            return HttpResponse::Ok().json(BearerTokens {
+                token_type: "Bearer",
+                access_token: claims.to_token(),
+                access_token_expires_at: claims.exp,
+                refresh_token_expires_at: session.expiry,
+                refresh_token: session.token,
+            });
+        }
+        let max_age = Duration::seconds(session.ttl() as i64);
+        HttpResponse::Ok()
+            .cookie(refresh_token_cookie(session.token, max_age))
+            .cookie(access_token_cookie(user))
+            .cookie(csrf_cookie(max_age))
+            .json(SuccessMessage { message })
+    }
+
+    /// Create a new session for `user` and respond with its tokens, called once the user has
+    /// fully proven who they are
+    pub fn start_session(req: &HttpRequest, redis_pool: &RedisPool, user: User) -> HttpResponse {
+        if user.disabled {
+            return auth::ErrorVariants::AccountDisabled
+                .to_error()
+                .error_response();
+        }
+        let useragent = req
+            .headers()
+            .get("User-Agent")
+            .and_then(|ua| ua.to_str().ok())
+            .unwrap_or_default();
+        // Construct session (from user's id, useragent and IP) and store it
+        let session = UserSession::new(user.id, useragent, super::net::client_ip(req));
+        if let Err(e) = session.set_session(redis_pool) {
+            return e.error_response();
+        }
+        record_event(req, Some(user.id), AuthEventKind::Login, None);
+        session_response(req, user, session, "Successfully Logged in")
+    }
+
+    /// Finish a login once the user has proven who they are with their first factor, either
+    /// starting a session or (if they have two-factor authentication enabled) handing them a
+    /// challenge to complete with a code instead
+    pub fn finish_login(req: &HttpRequest, redis_pool: &RedisPool, user: User) -> HttpResponse {
+        if user.disabled {
+            return auth::ErrorVariants::AccountDisabled
+                .to_error()
+                .error_response();
+        }
+        if !user.totp_enabled {
+            return start_session(req, redis_pool, user);
+        }
+        match LoginChallenge::issue(redis_pool, user.id) {
+            Ok(challenge) => HttpResponse::Ok().json(TwoFactorChallenge {
+                two_factor_required: true,
+                challenge,
+            }),
+            Err(e) => e.error_response(),
+        }
+    }
+
+    /// Refresh tokens used to be set on the "/" path, which a cookie on `REFRESH_COOKIE_PATH`
+    /// doesn't replace, so those have to be removed separately
+    const LEGACY_REFRESH_COOKIE_PATH: &str = "/";
+
+    /// Put the secret binding an external sign in to this browser in a cookie that lasts as long
+    /// as the sign in request. It has to be sent on the provider's (cross-site) redirect back, so
+    /// it is always `SameSite=Lax`
+    pub fn oidc_cookie(secret: String) -> Cookie<'static> {
+        auth_cookie(OIDC_COOKIE, secret, OIDC_COOKIE_PATH)
+            .http_only(true)
+            .same_site(SameSite::Lax)
+            .max_age(Duration::seconds(STATE_LIFE as i64))
+            .finish()
+    }
+
+    /// Build a version of the "oidc_browser" cookie that removes it, once the sign in it was for
+    /// is over
+    pub fn oidc_removal_cookie() -> Cookie<'static> {
+        auth_cookie(OIDC_COOKIE, String::new(), OIDC_COOKIE_PATH)
+            .http_only(true)
+            .same_site(SameSite::Lax)
+            .max_age(Duration::zero())
+            .expires(OffsetDateTime::now_utc() - Duration::days(365))
+            .finish()
+    }
+
+    /// Remove the "refresh_token", "access_token" and "csrf_token" cookies (with the same path and
+    /// domain they were set with) from the client
+    pub fn remove_auth_cookies(res: &mut HttpResponseBuilder) {
+        for cookie in [
+            auth_cookie("refresh_token", String::new(), REFRESH_COOKIE_PATH).finish(),
+            auth_cookie("access_token", String::new(), "/").finish(),
+            auth_cookie(CSRF_COOKIE, String::new(), "/").finish(),
+        ]
+        .iter()
+        {
+            res.del_cookie(cookie);
+        }
+        // The cookie jar only keeps one cookie per name, so this one goes in its own header
+        let legacy = auth_cookie("refresh_token", String::new(), LEGACY_REFRESH_COOKIE_PATH)
+            .max_age(Duration::zero())
+            .expires(OffsetDateTime::now_utc() - Duration::days(365))
+            .finish();
+        res.header(header::SET_COOKIE, legacy.to_string());
+    }
+
+    /// Issue an email verification token for the user with an id of `id` and email it to them,
+    /// failures are only logged since the user can always ask for another email
+    pub async fn send_verification_email(
+        mailer: Arc<dyn MailTransport>,
+        redis_pool: &RedisPool,
+        id: uuid::Uuid,
+        email: String,
+    ) {
+        let token = match issue_verification_token(redis_pool, id, &email) {
+            Ok(t) => t,
+            Err(e) => return eprintln!("Failed to issue verification token: {}", e),
+        };
+        let mail = Mail {
+            to: email,
+            subject: "Verify your email".to_string(),
+            body: format!(
+                "Please verify your email by visiting the link below:\n\n{}/api/users/auth/verify?token={}\n\nThe link expires in 24 hours.",
+                PUBLIC_URL.trim_end_matches('/'),
+                token
+            ),
+        };
+        // Sending can block for a while (e.g. talking to an SMTP server) so do it on the thread pool
+        if let Err(e) = actix_web::web::block(move || mailer.send(&mail)).await {
+            eprintln!("Failed to send verification email: {}", e);
+        }
+    }
+
+    /// Issue a password reset token for the user with an id of `id` and email it to them, failures
+    /// are only logged so that the response doesn't reveal whether the account exists
+    pub async fn send_password_reset_email(
+        mailer: Arc<dyn MailTransport>,
+        redis_pool: &RedisPool,
+        id: uuid::Uuid,
+        email: String,
+    ) {
+        let token = match issue_reset_token(redis_pool, id) {
+            Ok(t) => t,
+            Err(e) => return eprintln!("Failed to issue password reset token: {}", e),
+        };
+        let mail = Mail {
+            to: email,
+            subject: "Reset your password".to_string(),
+            body: format!(
+                "A password reset was requested for your account, you can choose a new password by visiting the link below:\n\n{}?token={}\n\nThe link expires in 30 minutes. If you didn't request this, you can ignore this email.",
+                PASSWORD_RESET_URL.as_str(),
+                token
+            ),
+        };
+        if let Err(e) = actix_web::web::block(move || mailer.send(&mail)).await {
+            eprintln!("Failed to send password reset email: {}", e);
+        }
+    }
+
+    /// Lets handlers require an authenticated user by taking `UserClaims` as an argument, the
+    /// claims come from a personal access token or access JWT in the "Authorization: Bearer"
+    /// header or else the "access_token" cookie and a 401 is sent back if it is missing, invalid
+    /// or expired (or a 403 if the user hasn't verified their email yet or their account is
+    /// disabled)
+    impl FromRequest for UserClaims {
+        type Error = Error;
+        type Future = Pin<Box<dyn Future<Output = Result<Self, Self::Error>>>>;
+        type Config = ();
+
+        fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+            let bearer = req
+                .headers()
+                .get(header::AUTHORIZATION)
+                .and_then(|h| h.to_str().ok())
+                .and_then(|h| h.strip_prefix("Bearer "))
+                .map(|t| t.trim().to_string());
+            let pool = req.app_data::<web::Data<sqlx::PgPool>>().cloned();
+            let cookie = req.cookie("access_token");
+            Box::pin(async move {
+                let pool =
+                    pool.ok_or_else(|| internal_server::ErrorVariants::DBError.to_error())?;
+                let mut claims = match bearer {
+                    // Personal access tokens have to be looked up in the database
+                    Some(token) if token.starts_with(TOKEN_PREFIX) => {
+                        AccessToken::authenticate(pool.as_ref(), &token).await?
+                    }
+                    // Otherwise it is an access JWT from a client in bearer mode
+                    Some(token) => UserClaims::from_token(&token)?,
+                    None => cookie
+                        .ok_or_else(|| auth::ErrorVariants::MissingAccessToken.to_error())
+                        .and_then(|c| UserClaims::from_token(c.value()))?,
+                };
+                // Access JWTs can't be taken back, so whether the account has been disabled (or
+                // had its role or email changed) since the token was issued is checked on every
+                // request
+                let status = User::get_status(pool.as_ref(), claims.id).await?;
+                if status.disabled {
+                    return Err(auth::ErrorVariants::AccountDisabled.to_error());
+                }
+                claims.role = status.role;
+                claims.email_verified = status.email_verified;
+                if claims.email_verified {
+                    Ok(claims)
+                } else {
+                    Err(auth::ErrorVariants::EmailNotVerified.to_error())
+                }
+            })
+        }
+    }
+}
+
+pub mod net {
+    use actix_web::HttpRequest;
+    use lazy_static::lazy_static;
+    use std::{env, net::IpAddr};
+
+    lazy_static! {
+        /// Proxies whose "X-Forwarded-For" header is trusted, configured with `TRUSTED_PROXIES` (a
+        /// comma separated list of IPs and/or CIDR ranges, e.g. "127.0.0.1,10.0.0.0/8")
+        static ref TRUSTED_PROXIES: Vec<(IpAddr, u8)> = env::var("TRUSTED_PROXIES")
+            .unwrap_or_default()
+            .split(',')
+            .map(str::trim)
+            .filter(|proxy| !proxy.is_empty())
+            .map(|proxy| {
+                parse_network(proxy)
+                    .unwrap_or_else(|| panic!("Invalid TRUSTED_PROXIES entry \"{}\"", proxy))
+            })
+            .collect();
+    }
+
+    /// Parse an IP or CIDR range into a network address and prefix length
+    fn parse_network(network: &str) -> Option<(IpAddr, u8)> {
+        let mut parts = network.splitn(2, '/');
+        let addr: IpAddr = parts.next()?.parse().ok()?;
+        let max_prefix = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match parts.next() {
+            Some(prefix) => prefix.parse().ok().filter(|p| *p <= max_prefix)?,
+            None => max_prefix,
+        };
+        Some((addr, prefix))
+    }
+
+    /// Whether `ip` is in the network `network`/`prefix`
+    fn in_network(ip: IpAddr, network: IpAddr, prefix: u8) -> bool {
+        match (ip, network) {
+            (IpAddr::V4(ip), IpAddr::V4(network)) => {
+                let mask = u32::MAX.checked_shl(32 - prefix as u32).unwrap_or(0);
+                u32::from(ip) & mask == u32::from(network) & mask
+            }
+            (IpAddr::V6(ip), IpAddr::V6(network)) => {
+                let mask = u128::MAX.checked_shl(128 - prefix as u32).unwrap_or(0);
+                u128::from(ip) & mask == u128::from(network) & mask
+            }
+            _ => false,
+        }
+    }
+
+    fn is_trusted(ip: IpAddr) -> bool {
+        TRUSTED_PROXIES
+            .iter()
+            .any(|(network, prefix)| in_network(ip, *network, *prefix))
+    }
+
+    /// Get the IP of the client that made the request. "X-Forwarded-For" is only looked at when
+    /// the request came from a trusted proxy, and then the client is the right-most address that
+    /// isn't a trusted proxy (anything to the left of it could have been made up by the client)
+    pub fn client_ip(req: &HttpRequest) -> Option<String> {
+        let mut ip = req.peer_addr()?.ip();
+        if is_trusted(ip) {
+            let forwarded: Vec<IpAddr> = req
+                .headers()
+                .get_all("X-Forwarded-For")
+                .filter_map(|h| h.to_str().ok())
+                .flat_map(|h| h.split(','))
+                .filter_map(|addr| addr.trim().parse().ok())
+                .collect();
+            for addr in forwarded.into_iter().rev() {
+                ip = addr;
+                if !is_trusted(addr) {
+                    break;
+                }
+            }
         }
+        Some(ip.to_string())
     }
 }
@@ -1,2 +1,10 @@
+pub mod admin;
 pub mod auth;
+pub mod categories;
 pub mod helpers;
+pub mod oidc;
+pub mod todos;
+pub mod tokens;
+pub mod two_factor;
+pub mod user;
+pub mod well_known;
@@ -0,0 +1,224 @@
+use super::helpers::auth::*;
+use crate::errors::{auth, Error};
+use crate::mail::MailTransport;
+use crate::models::{identity::UserIdentity, oidc::*, session::RedisPool, user::*};
+use actix_web::{
+    delete, get, http::header, web, HttpMessage, HttpRequest, HttpResponse, Responder,
+    ResponseError,
+};
+use std::sync::Arc;
+
+/// Redirect the user to `provider`'s authorization page
+async fn redirect_to_provider(
+    redis_pool: &RedisPool,
+    provider: &str,
+    link_user: Option<uuid::Uuid>,
+) -> HttpResponse {
+    let url = match OidcProvider::get(provider) {
+        Ok(p) => p.authorization_url(redis_pool, link_user).await,
+        Err(e) => Err(e),
+    };
+    match url {
+        Ok((url, browser_secret)) => HttpResponse::Found()
+            .header(header::LOCATION, url)
+            .cookie(oidc_cookie(browser_secret))
+            .finish(),
+        Err(e) => e.error_response(),
+    }
+}
+
+#[get("/{provider}/login")]
+/// Starts signing in with an external identity provider
+pub async fn login(
+    redis_pool: web::Data<r2d2::Pool<redis::Client>>,
+    provider: web::Path<String>,
+) -> impl Responder {
+    redirect_to_provider(redis_pool.into_inner().as_ref(), &provider, None).await
+}
+
+#[get("/{provider}/link")]
+/// Starts linking an external identity provider to the logged in user's account
+pub async fn link(
+    redis_pool: web::Data<r2d2::Pool<redis::Client>>,
+    claims: UserClaims,
+    provider: web::Path<String>,
+) -> impl Responder {
+    if let Err(e) = claims.require_session() {
+        return e.error_response();
+    }
+    redirect_to_provider(redis_pool.into_inner().as_ref(), &provider, Some(claims.id)).await
+}
+
+/// Get the user an identity belongs to, creating an account for it if it's new
+async fn find_or_create_user(
+    pool: &sqlx::PgPool,
+    redis_pool: &RedisPool,
+    mailer: Arc<dyn MailTransport>,
+    provider: &str,
+    claims: IdTokenClaims,
+) -> Result<User, Error> {
+    if let Some(id) = UserIdentity::get_user_id(pool, provider, &claims.sub).await? {
+        return User::get_by_id(pool, id).await;
+    }
+    let email = claims
+        .email
+        .ok_or_else(|| auth::ErrorVariants::OidcLoginFailed.to_error())?;
+    // Accounts aren't linked automatically by email, otherwise anyone that can get a provider to
+    // vouch for an email could take over the matching account
+    match User::get_by_email(pool, email.clone()).await {
+        Ok(_) => return Err(auth::ErrorVariants::IdentityEmailTaken.to_error()),
+        Err(e) if e.error.kind != "AuthError" => return Err(e),
+        Err(_) => (),
+    }
+    let username = claims
+        .preferred_username
+        .unwrap_or_else(|| email.split('@').next().unwrap_or_default().to_string());
+    let email_verified = claims.email_verified.unwrap_or(false);
+    let user = User::insert_external(
+        pool,
+        claims.name.as_deref(),
+        &username,
+        &email,
+        email_verified,
+    )
+    .await?;
+    UserIdentity::link(pool, user.id, provider, &claims.sub, Some(&email)).await?;
+    if !email_verified {
+        send_verification_email(mailer, redis_pool, user.id, email).await;
+    }
+    Ok(user)
+}
+
+#[get("/{provider}/callback")]
+/// Where the provider sends the user back to, either logs them in (creating an account on their
+/// first sign in) or links the identity to the account that started the request
+pub async fn callback(
+    pool: web::Data<sqlx::PgPool>,
+    redis_pool: web::Data<r2d2::Pool<redis::Client>>,
+    mailer: web::Data<dyn MailTransport>,
+    req: HttpRequest,
+    provider: web::Path<String>,
+    query: web::Query<OidcCallback>,
+) -> impl Responder {
+    let mut res = complete_sign_in(
+        pool.into_inner().as_ref(),
+        redis_pool.into_inner().as_ref(),
+        Arc::clone(&mailer),
+        &req,
+        &provider,
+        query.into_inner(),
+    )
+    .await;
+    // The sign in request is gone either way, so the browser doesn't need its cookie anymore
+    if let Err(e) = res.add_cookie(&oidc_removal_cookie()) {
+        eprintln!("Failed to remove OIDC cookie: {}", e);
+    }
+    res
+}
+
+/// Finish the sign in started by this browser (see `callback`)
+async fn complete_sign_in(
+    pool: &sqlx::PgPool,
+    redis_pool: &RedisPool,
+    mailer: Arc<dyn MailTransport>,
+    req: &HttpRequest,
+    provider: &str,
+    query: OidcCallback,
+) -> HttpResponse {
+    let browser_secret = req
+        .cookie(OIDC_COOKIE)
+        .map(|c| c.value().to_string())
+        .unwrap_or_default();
+    let state = match OidcState::take(redis_pool, &query.state, &browser_secret) {
+        Ok(s) if s.provider == provider => s,
+        Ok(_) => {
+            return auth::ErrorVariants::InvalidOidcState
+                .to_error()
+                .error_response()
+        }
+        Err(e) => return e.error_response(),
+    };
+    let code = match (query.code, query.error) {
+        (Some(code), None) => code,
+        (_, error) => {
+            eprintln!("OIDC sign in failed: {}", error.unwrap_or_default());
+            return auth::ErrorVariants::OidcLoginFailed
+                .to_error()
+                .error_response();
+        }
+    };
+    let claims = match OidcProvider::get(provider) {
+        Ok(p) => p.exchange_code(&code, &state).await,
+        Err(e) => Err(e),
+    };
+    let claims = match claims {
+        Ok(c) => c,
+        Err(e) => return e.error_response(),
+    };
+    if let Some(user_id) = state.link_user {
+        return match UserIdentity::link(
+            pool,
+            user_id,
+            provider,
+            &claims.sub,
+            claims.email.as_deref(),
+        )
+        .await
+        {
+            Ok(()) => HttpResponse::Ok().json(SuccessMessage {
+                message: "Successfully linked identity provider",
+            }),
+            Err(e) => e.error_response(),
+        };
+    }
+    match find_or_create_user(pool, redis_pool, mailer, provider, claims).await {
+        Ok(user) => finish_login(req, redis_pool, user),
+        Err(e) => e.error_response(),
+    }
+}
+
+#[get("/identities")]
+/// Lists the identity providers linked to the user's account
+pub async fn identities(pool: web::Data<sqlx::PgPool>, claims: UserClaims) -> impl Responder {
+    if let Err(e) = claims.require_session() {
+        return e.error_response();
+    }
+    match UserIdentity::get_all(pool.into_inner().as_ref(), claims.id).await {
+        Ok(linked) => HttpResponse::Ok().json(linked),
+        Err(e) => e.error_response(),
+    }
+}
+
+#[delete("/{provider}")]
+/// Unlinks an identity provider from the user's account (unless it's the only way they can log in)
+pub async fn unlink(
+    pool: web::Data<sqlx::PgPool>,
+    claims: UserClaims,
+    provider: web::Path<String>,
+) -> impl Responder {
+    if let Err(e) = claims.require_session() {
+        return e.error_response();
+    }
+    let pool = pool.into_inner();
+    let user = match User::get_by_id(pool.as_ref(), claims.id).await {
+        Ok(u) => u,
+        Err(e) => return e.error_response(),
+    };
+    if user.password.is_none() {
+        match UserIdentity::get_all(pool.as_ref(), user.id).await {
+            Ok(linked) if linked.len() <= 1 => {
+                return auth::ErrorVariants::LastLoginMethod
+                    .to_error()
+                    .error_response()
+            }
+            Ok(_) => (),
+            Err(e) => return e.error_response(),
+        }
+    }
+    match UserIdentity::unlink(pool.as_ref(), user.id, &provider).await {
+        Ok(()) => HttpResponse::Ok().json(SuccessMessage {
+            message: "Successfully unlinked identity provider",
+        }),
+        Err(e) => e.error_response(),
+    }
+}
@@ -0,0 +1,145 @@
+use super::helpers::auth::SuccessMessage;
+use crate::models::{access_token::Scope, category::Category, todo::*, user::UserClaims};
+use crate::validation::Validate;
+use actix_web::{delete, get, post, put, web, HttpResponse, Responder, ResponseError};
+
+#[get("")]
+/// Lists the todos in one of the user's categories (optionally filtered)
+pub async fn get_all(
+    pool: web::Data<sqlx::PgPool>,
+    claims: UserClaims,
+    cat_id: web::Path<uuid::Uuid>,
+    query: web::Query<TodoQuery>,
+) -> impl Responder {
+    if let Err(e) = claims.require_scope(Scope::TodosRead) {
+        return e.error_response();
+    }
+    let query = query.into_inner();
+    // Going through the category means a category that isn't the user's gets a 404 rather than
+    // an empty list
+    match Category::get_with_todos(
+        pool.into_inner().as_ref(),
+        claims.id,
+        cat_id.into_inner(),
+        query.filter,
+        query.limit,
+    )
+    .await
+    {
+        Ok(category) => HttpResponse::Ok().json(category.todos),
+        Err(e) => e.error_response(),
+    }
+}
+
+#[post("")]
+/// Creates a todo in one of the user's categories
+pub async fn create(
+    pool: web::Data<sqlx::PgPool>,
+    claims: UserClaims,
+    cat_id: web::Path<uuid::Uuid>,
+    body: web::Json<TodoInsert>,
+) -> impl Responder {
+    if let Err(e) = claims.require_scope(Scope::TodosWrite) {
+        return e.error_response();
+    }
+    // Validate request body
+    let todo = body.into_inner();
+    if let Some(e) = todo.validate() {
+        return HttpResponse::BadRequest().json(e.to_error());
+    }
+    match Todo::insert(
+        pool.into_inner().as_ref(),
+        claims.id,
+        cat_id.into_inner(),
+        todo,
+    )
+    .await
+    {
+        Ok(todo) => HttpResponse::Ok().json(todo),
+        Err(e) => e.error_response(),
+    }
+}
+
+#[get("/{todo_id}")]
+/// Gets one of the user's todos
+pub async fn get(
+    pool: web::Data<sqlx::PgPool>,
+    claims: UserClaims,
+    path: web::Path<(uuid::Uuid, uuid::Uuid)>,
+) -> impl Responder {
+    if let Err(e) = claims.require_scope(Scope::TodosRead) {
+        return e.error_response();
+    }
+    let (cat_id, todo_id) = path.into_inner();
+    match Todo::get(pool.into_inner().as_ref(), claims.id, cat_id, todo_id).await {
+        Ok(todo) => HttpResponse::Ok().json(todo),
+        Err(e) => e.error_response(),
+    }
+}
+
+#[put("/{todo_id}/toggle")]
+/// Flips whether one of the user's todos is completed
+pub async fn toggle(
+    pool: web::Data<sqlx::PgPool>,
+    claims: UserClaims,
+    path: web::Path<(uuid::Uuid, uuid::Uuid)>,
+) -> impl Responder {
+    if let Err(e) = claims.require_scope(Scope::TodosWrite) {
+        return e.error_response();
+    }
+    let (cat_id, todo_id) = path.into_inner();
+    match Todo::toggle(pool.into_inner().as_ref(), claims.id, cat_id, todo_id).await {
+        Ok(todo) => HttpResponse::Ok().json(todo),
+        Err(e) => e.error_response(),
+    }
+}
+
+#[put("/{todo_id}")]
+/// Updates one of the user's todos
+pub async fn update(
+    pool: web::Data<sqlx::PgPool>,
+    claims: UserClaims,
+    path: web::Path<(uuid::Uuid, uuid::Uuid)>,
+    body: web::Json<TodoUpdate>,
+) -> impl Responder {
+    if let Err(e) = claims.require_scope(Scope::TodosWrite) {
+        return e.error_response();
+    }
+    // Validate request body
+    let update = body.into_inner();
+    if let Some(e) = update.validate() {
+        return HttpResponse::BadRequest().json(e.to_error());
+    }
+    let (cat_id, todo_id) = path.into_inner();
+    match Todo::update(
+        pool.into_inner().as_ref(),
+        claims.id,
+        cat_id,
+        todo_id,
+        update,
+    )
+    .await
+    {
+        Ok(todo) => HttpResponse::Ok().json(todo),
+        Err(e) => e.error_response(),
+    }
+}
+
+#[delete("/{todo_id}")]
+/// Deletes one of the user's todos
+pub async fn delete(
+    pool: web::Data<sqlx::PgPool>,
+    claims: UserClaims,
+    path: web::Path<(uuid::Uuid, uuid::Uuid)>,
+) -> impl Responder {
+    if let Err(e) = claims.require_scope(Scope::TodosWrite) {
+        return e.error_response();
+    }
+    let (cat_id, todo_id) = path.into_inner();
+    match Todo::delete(pool.into_inner().as_ref(), claims.id, cat_id, todo_id).await {
+        Ok(()) => HttpResponse::Ok().json(SuccessMessage {
+            message: "Successfully deleted todo",
+        }),
+        Err(e) => e.error_response(),
+    }
+}
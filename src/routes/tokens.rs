@@ -0,0 +1,55 @@
+use super::helpers::auth::SuccessMessage;
+use crate::models::{access_token::*, user::UserClaims};
+use crate::validation::Validate;
+use actix_web::{delete, get, post, web, HttpResponse, Responder, ResponseError};
+
+#[get("")]
+/// Lists the user's personal access tokens (without the tokens themselves)
+pub async fn list(pool: web::Data<sqlx::PgPool>, claims: UserClaims) -> impl Responder {
+    if let Err(e) = claims.require_session() {
+        return e.error_response();
+    }
+    match AccessToken::get_all(pool.into_inner().as_ref(), claims.id).await {
+        Ok(tokens) => HttpResponse::Ok().json(tokens),
+        Err(e) => e.error_response(),
+    }
+}
+
+#[post("")]
+/// Creates a personal access token, the token is only ever shown in this response
+pub async fn create(
+    pool: web::Data<sqlx::PgPool>,
+    claims: UserClaims,
+    body: web::Json<AccessTokenInsert>,
+) -> impl Responder {
+    if let Err(e) = claims.require_session() {
+        return e.error_response();
+    }
+    // Validate request body
+    let token = body.into_inner();
+    if let Some(e) = token.validate() {
+        return HttpResponse::BadRequest().json(e.to_error());
+    }
+    match AccessToken::insert(pool.into_inner().as_ref(), claims.id, token).await {
+        Ok(token) => HttpResponse::Ok().json(token),
+        Err(e) => e.error_response(),
+    }
+}
+
+#[delete("/{token_id}")]
+/// Revokes one of the user's personal access tokens
+pub async fn revoke(
+    pool: web::Data<sqlx::PgPool>,
+    claims: UserClaims,
+    token_id: web::Path<uuid::Uuid>,
+) -> impl Responder {
+    if let Err(e) = claims.require_session() {
+        return e.error_response();
+    }
+    match AccessToken::revoke(pool.into_inner().as_ref(), claims.id, token_id.into_inner()).await {
+        Ok(()) => HttpResponse::Ok().json(SuccessMessage {
+            message: "Successfully revoked access token",
+        }),
+        Err(e) => e.error_response(),
+    }
+}
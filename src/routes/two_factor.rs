@@ -0,0 +1,205 @@
+use super::helpers::auth::*;
+use crate::errors::{auth, Error};
+use crate::models::{
+    session::RedisPool,
+    throttle::{self, ThrottleKey},
+    totp::{self, *},
+    user::*,
+};
+use actix_web::{post, web, HttpResponse, Responder, ResponseError};
+
+/// Check a TOTP code (or, if `allow_recovery` is set, a recovery code) for a user that has
+/// two-factor authentication enabled
+async fn check_code(
+    pool: &sqlx::PgPool,
+    redis_pool: &RedisPool,
+    user: &User,
+    code: &str,
+    allow_recovery: bool,
+) -> Result<(), Error> {
+    let secret = match &user.totp_secret {
+        Some(s) if user.totp_enabled => s,
+        _ => return Err(auth::ErrorVariants::TwoFactorNotEnabled.to_error()),
+    };
+    if totp::verify_code(redis_pool, user.id, secret, code)?
+        || (allow_recovery && RecoveryCode::consume(pool, user.id, code).await?)
+    {
+        Ok(())
+    } else {
+        Err(auth::ErrorVariants::InvalidTwoFactorCode.to_error())
+    }
+}
+
+#[post("/enroll")]
+/// Starts two-factor enrollment by generating a new TOTP secret, which only takes effect once it
+/// has been confirmed with a code
+pub async fn enroll(pool: web::Data<sqlx::PgPool>, claims: UserClaims) -> impl Responder {
+    if let Err(e) = claims.require_session() {
+        return e.error_response();
+    }
+    let pool = pool.into_inner();
+    let user = match User::get_by_id(pool.as_ref(), claims.id).await {
+        Ok(u) => u,
+        Err(e) => return e.error_response(),
+    };
+    if user.totp_enabled {
+        return auth::ErrorVariants::TwoFactorAlreadyEnabled
+            .to_error()
+            .error_response();
+    }
+    let secret = generate_secret();
+    if let Err(e) = User::set_totp_secret(pool.as_ref(), user.id, Some(secret.clone())).await {
+        return e.error_response();
+    }
+    HttpResponse::Ok().json(TwoFactorEnrollment {
+        otpauth_uri: otpauth_uri(&secret, &user.username),
+        secret,
+    })
+}
+
+#[post("/confirm")]
+/// Confirms two-factor enrollment with a code from the authenticator app, switching it on and
+/// returning the user's recovery codes
+pub async fn confirm(
+    pool: web::Data<sqlx::PgPool>,
+    redis_pool: web::Data<r2d2::Pool<redis::Client>>,
+    claims: UserClaims,
+    body: web::Json<TwoFactorCode>,
+) -> impl Responder {
+    if let Err(e) = claims.require_session() {
+        return e.error_response();
+    }
+    let pool = pool.into_inner();
+    let user = match User::get_by_id(pool.as_ref(), claims.id).await {
+        Ok(u) => u,
+        Err(e) => return e.error_response(),
+    };
+    if user.totp_enabled {
+        return auth::ErrorVariants::TwoFactorAlreadyEnabled
+            .to_error()
+            .error_response();
+    }
+    let secret = match &user.totp_secret {
+        Some(s) => s,
+        None => {
+            return auth::ErrorVariants::TwoFactorNotEnabled
+                .to_error()
+                .error_response()
+        }
+    };
+    // Guessing codes is throttled the same way as logging in
+    let redis_pool = redis_pool.into_inner();
+    let key = [ThrottleKey::Username(&user.username)];
+    if let Err(e) = throttle::begin_attempt(redis_pool.as_ref(), &key) {
+        return e.error_response();
+    }
+    match totp::verify_code(redis_pool.as_ref(), user.id, secret, &body.code) {
+        Ok(true) => (),
+        Ok(false) => {
+            return auth::ErrorVariants::InvalidTwoFactorCode
+                .to_error()
+                .error_response()
+        }
+        Err(e) => return e.error_response(),
+    }
+    if let Err(e) = throttle::forgive_attempt(redis_pool.as_ref(), &key) {
+        return e.error_response();
+    }
+    let recovery_codes = match RecoveryCode::regenerate(pool.as_ref(), user.id).await {
+        Ok(c) => c,
+        Err(e) => return e.error_response(),
+    };
+    if let Err(e) = User::enable_totp(pool.as_ref(), user.id).await {
+        return e.error_response();
+    }
+    HttpResponse::Ok().json(RecoveryCodes { recovery_codes })
+}
+
+#[post("/disable")]
+/// Switches off two-factor authentication, which needs both the user's password and a TOTP or
+/// recovery code
+pub async fn disable(
+    pool: web::Data<sqlx::PgPool>,
+    redis_pool: web::Data<r2d2::Pool<redis::Client>>,
+    claims: UserClaims,
+    body: web::Json<TwoFactorDisable>,
+) -> impl Responder {
+    if let Err(e) = claims.require_session() {
+        return e.error_response();
+    }
+    let pool = pool.into_inner();
+    let user = match User::get_by_id(pool.as_ref(), claims.id).await {
+        Ok(u) => u,
+        Err(e) => return e.error_response(),
+    };
+    // A password cleared by an admin doesn't make this an account without one
+    if user.password_reset_required {
+        return auth::ErrorVariants::PasswordResetRequired
+            .to_error()
+            .error_response();
+    }
+    // Guessing the password or code is throttled the same way as logging in
+    let redis_pool = redis_pool.into_inner();
+    let key = [ThrottleKey::Username(&user.username)];
+    if let Err(e) = throttle::begin_attempt(redis_pool.as_ref(), &key) {
+        return e.error_response();
+    }
+    // Accounts without a password only have the code to prove who they are
+    let body = body.into_inner();
+    if user.password.is_some() {
+        let password = body.password.unwrap_or_default();
+        if let Err(e) = check_password_hash(&password, user.password.as_deref()) {
+            return e.error_response();
+        }
+    }
+    if let Err(e) = check_code(pool.as_ref(), redis_pool.as_ref(), &user, &body.code, true).await {
+        return e.error_response();
+    }
+    if let Err(e) = throttle::forgive_attempt(redis_pool.as_ref(), &key) {
+        return e.error_response();
+    }
+    if let Err(e) = User::set_totp_secret(pool.as_ref(), user.id, None).await {
+        return e.error_response();
+    }
+    if let Err(e) = RecoveryCode::delete_all(pool.as_ref(), user.id).await {
+        return e.error_response();
+    }
+    HttpResponse::Ok().json(SuccessMessage {
+        message: "Successfully disabled two-factor authentication",
+    })
+}
+
+#[post("/recovery-codes")]
+/// Replaces the user's recovery codes with new ones (needs a TOTP code, so a lost set of codes
+/// can't be used to make more)
+pub async fn regenerate_recovery_codes(
+    pool: web::Data<sqlx::PgPool>,
+    redis_pool: web::Data<r2d2::Pool<redis::Client>>,
+    claims: UserClaims,
+    body: web::Json<TwoFactorCode>,
+) -> impl Responder {
+    if let Err(e) = claims.require_session() {
+        return e.error_response();
+    }
+    let pool = pool.into_inner();
+    let user = match User::get_by_id(pool.as_ref(), claims.id).await {
+        Ok(u) => u,
+        Err(e) => return e.error_response(),
+    };
+    // Guessing codes is throttled the same way as logging in
+    let redis_pool = redis_pool.into_inner();
+    let key = [ThrottleKey::Username(&user.username)];
+    if let Err(e) = throttle::begin_attempt(redis_pool.as_ref(), &key) {
+        return e.error_response();
+    }
+    if let Err(e) = check_code(pool.as_ref(), redis_pool.as_ref(), &user, &body.code, false).await {
+        return e.error_response();
+    }
+    if let Err(e) = throttle::forgive_attempt(redis_pool.as_ref(), &key) {
+        return e.error_response();
+    }
+    match RecoveryCode::regenerate(pool.as_ref(), user.id).await {
+        Ok(recovery_codes) => HttpResponse::Ok().json(RecoveryCodes { recovery_codes }),
+        Err(e) => e.error_response(),
+    }
+}
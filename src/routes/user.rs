@@ -0,0 +1,137 @@
+use super::helpers::auth::*;
+use crate::errors::auth;
+use crate::models::{
+    now,
+    session::UserSession,
+    throttle::{self, ThrottleKey},
+    user::*,
+};
+use crate::validation::Validate;
+use actix_web::{delete, get, post, put, web, HttpResponse, Responder, ResponseError};
+use lazy_static::lazy_static;
+use std::env;
+
+lazy_static! {
+    /// Number of days between a user asking for their account to be deleted and it actually being
+    /// deleted (during which they can cancel), 0 deletes accounts immediately
+    static ref DELETION_GRACE_DAYS: i64 = env::var("ACCOUNT_DELETION_GRACE_DAYS")
+        .map(|d| d.parse().expect("ACCOUNT_DELETION_GRACE_DAYS must be a number"))
+        .unwrap_or(0);
+}
+
+#[get("")]
+/// Gets the user's profile
+pub async fn get_profile(pool: web::Data<sqlx::PgPool>, claims: UserClaims) -> impl Responder {
+    match User::get_profile(pool.into_inner().as_ref(), claims.id).await {
+        Ok(profile) => HttpResponse::Ok().json(profile),
+        Err(e) => e.error_response(),
+    }
+}
+
+#[put("")]
+/// Updates the user's profile (displayname, username and bio), sending back the updated profile
+/// along with a new "access_token" cookie since the old one has the old details in it
+pub async fn update_profile(
+    pool: web::Data<sqlx::PgPool>,
+    claims: UserClaims,
+    body: web::Json<UserUpdate>,
+) -> impl Responder {
+    if let Err(e) = claims.require_session() {
+        return e.error_response();
+    }
+    // Validate request body
+    let update = body.into_inner();
+    if let Some(e) = update.validate() {
+        return HttpResponse::BadRequest().json(e.to_error());
+    }
+    let pool = pool.into_inner();
+    let user = match User::update(pool.as_ref(), claims.id, update).await {
+        Ok(u) => u,
+        Err(e) => return e.error_response(),
+    };
+    match User::get_profile(pool.as_ref(), user.id).await {
+        Ok(profile) => HttpResponse::Ok()
+            .cookie(access_token_cookie(user))
+            .json(profile),
+        Err(e) => e.error_response(),
+    }
+}
+
+#[delete("")]
+/// Deletes the user's account (after the grace period, if there is one) along with all their data
+pub async fn delete_account(
+    pool: web::Data<sqlx::PgPool>,
+    redis_pool: web::Data<r2d2::Pool<redis::Client>>,
+    claims: UserClaims,
+    body: web::Json<AccountDeletion>,
+) -> impl Responder {
+    if let Err(e) = claims.require_session() {
+        return e.error_response();
+    }
+    let pool = pool.into_inner();
+    let user = match User::get_by_id(pool.as_ref(), claims.id).await {
+        Ok(u) => u,
+        Err(e) => return e.error_response(),
+    };
+    // A password cleared by an admin doesn't make this an account without one, the user has to
+    // set a new password (with a password reset) and confirm that instead
+    if user.password_reset_required {
+        return auth::ErrorVariants::PasswordResetRequired
+            .to_error()
+            .error_response();
+    }
+    // Users with a password have to confirm it (guesses are throttled the same way as logging in)
+    let redis_pool = redis_pool.into_inner();
+    if user.password.is_some() {
+        let key = [ThrottleKey::Username(&user.username)];
+        if let Err(e) = throttle::begin_attempt(redis_pool.as_ref(), &key) {
+            return e.error_response();
+        }
+        let password = body.into_inner().password.unwrap_or_default();
+        if let Err(e) = check_password_hash(&password, user.password.as_deref()) {
+            return e.error_response();
+        }
+        if let Err(e) = throttle::forgive_attempt(redis_pool.as_ref(), &key) {
+            return e.error_response();
+        }
+    }
+    if *DELETION_GRACE_DAYS > 0 {
+        let now = now();
+        let deletion_scheduled_at = now + *DELETION_GRACE_DAYS * 24 * 60 * 60;
+        return match User::schedule_deletion(pool.as_ref(), user.id, deletion_scheduled_at).await {
+            Ok(()) => HttpResponse::Accepted().json(DeletionScheduled {
+                deletion_scheduled_at,
+            }),
+            Err(e) => e.error_response(),
+        };
+    }
+    if let Err(e) = User::delete(pool.as_ref(), user.id).await {
+        return e.error_response();
+    }
+    // The sessions can't be refreshed once the user is gone, but remove them from redis anyway
+    if let Err(e) = UserSession::revoke_all(redis_pool.as_ref(), user.id) {
+        eprintln!(
+            "Failed to revoke sessions of deleted user {}: {}",
+            user.id, e
+        );
+    }
+    let mut res = HttpResponse::Ok();
+    remove_auth_cookies(&mut res);
+    res.json(SuccessMessage {
+        message: "Account successfully deleted",
+    })
+}
+
+#[post("/deletion/cancel")]
+/// Cancels a scheduled account deletion
+pub async fn cancel_deletion(pool: web::Data<sqlx::PgPool>, claims: UserClaims) -> impl Responder {
+    if let Err(e) = claims.require_session() {
+        return e.error_response();
+    }
+    match User::cancel_deletion(pool.into_inner().as_ref(), claims.id).await {
+        Ok(()) => HttpResponse::Ok().json(SuccessMessage {
+            message: "Account deletion successfully cancelled",
+        }),
+        Err(e) => e.error_response(),
+    }
+}
@@ -0,0 +1,11 @@
+use crate::models::signing;
+use actix_web::{get, http::header, HttpResponse, Responder};
+
+#[get("/jwks.json")]
+/// Serves the public keys that access tokens are signed with, so other services can verify them
+/// without holding any secrets
+pub async fn jwks() -> impl Responder {
+    HttpResponse::Ok()
+        .header(header::CACHE_CONTROL, "public, max-age=300")
+        .json(signing::jwks())
+}
@@ -0,0 +1,23 @@
+use crate::errors::validation::{access_token::ErrorVariants, ValidationError};
+use crate::models::access_token::{AccessTokenInsert, Scope};
+
+impl super::Validate for AccessTokenInsert {
+    /// Validates a personal access token creation request body
+    fn validate(&self) -> Option<ValidationError> {
+        let name_len = self.name.chars().count();
+
+        Some(ErrorVariants::to_validation_error(
+            if !(1..=64).contains(&name_len) {
+                ErrorVariants::NameLength
+            } else if self.scopes.is_empty() {
+                ErrorVariants::ScopesEmpty
+            } else if self.scopes.iter().any(|s| Scope::parse(s).is_none()) {
+                ErrorVariants::ScopeInvalid
+            } else if matches!(self.expires_in_days, Some(d) if !(1..=365).contains(&d)) {
+                ErrorVariants::ExpiryRange
+            } else {
+                return None;
+            },
+        ))
+    }
+}
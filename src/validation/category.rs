@@ -0,0 +1,33 @@
+use crate::errors::validation::{category::ErrorVariants, ValidationError};
+use crate::models::category::{CategoryInsert, CategoryUpdate};
+
+/// Checks a category's name and description, either of which can be left out of an update
+fn validate_fields(name: Option<&str>, description: Option<&str>) -> Option<ValidationError> {
+    let name_len = name.map(|n| n.chars().count());
+    let description_len = description.map(|d| d.chars().count());
+
+    Some(ErrorVariants::to_validation_error(
+        if matches!(name_len, Some(len) if !(1..=128).contains(&len)) {
+            ErrorVariants::NameLength
+        } else if matches!(description_len, Some(len) if len > 2048) {
+            ErrorVariants::DescriptionLength
+        } else {
+            return None;
+        },
+    ))
+}
+
+impl super::Validate for CategoryInsert {
+    /// Validates a category creation request body
+    fn validate(&self) -> Option<ValidationError> {
+        validate_fields(Some(&self.name), Some(&self.description))
+    }
+}
+
+impl super::Validate for CategoryUpdate {
+    /// Validates a category update request body, only the fields that are being changed are
+    /// checked
+    fn validate(&self) -> Option<ValidationError> {
+        validate_fields(self.name.as_deref(), self.description.as_deref())
+    }
+}
@@ -2,20 +2,21 @@ use crate::errors::validation::{auth::ErrorVariants, ValidationError};
 use crate::models::user::UserLogin;
 
 impl super::Validate for UserLogin {
-    /// Validates a login request body
+    /// Validates a login request body, the identifier is checked as an email if it contains an
+    /// "@" and as a username otherwise
     fn validate(&self) -> Option<ValidationError> {
-        let un_len = self.username.len();
-        let em_len = self.email.len();
+        let id_len = self.identifier.len();
         let pass_len = self.password.len();
 
         Some(ErrorVariants::to_validation_error(
-            if un_len <= 2 || un_len > 128 {
-                ErrorVariants::UsernameLength
-            } else if em_len < 6 || em_len > 256 {
-                ErrorVariants::EmailLength
-            } else if !super::EMAIL_VALIDATOR.is_match(&self.email).unwrap() {
-                ErrorVariants::EmailInvalid
-            } else if pass_len < 8 || pass_len > 256 {
+            if self.is_email() && !(6..=256).contains(&id_len) {
+                ErrorVariants::IdentifierEmailLength
+            } else if self.is_email() && !super::EMAIL_VALIDATOR.is_match(&self.identifier).unwrap()
+            {
+                ErrorVariants::IdentifierEmailInvalid
+            } else if !self.is_email() && !(3..=128).contains(&id_len) {
+                ErrorVariants::IdentifierUsernameLength
+            } else if !(8..=256).contains(&pass_len) {
                 ErrorVariants::PasswordWeak
             } else {
                 return None;
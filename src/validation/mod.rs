@@ -2,8 +2,15 @@ use crate::errors::validation::ValidationError;
 use fancy_regex::Regex;
 use lazy_static::lazy_static;
 
+pub mod access_token;
+pub mod category;
 pub mod login;
+pub mod password_change;
+pub mod password_reset;
+pub mod profile;
 pub mod registration;
+pub mod session;
+pub mod todo;
 
 lazy_static! {
     static ref EMAIL_VALIDATOR: Regex = Regex::new(r#"^[^@\s]+@[^@\s]+\.[^@\.\s]+$"#).unwrap();
@@ -0,0 +1,17 @@
+use crate::errors::validation::{auth::ErrorVariants, ValidationError};
+use crate::models::user::PasswordChange;
+
+impl super::Validate for PasswordChange {
+    /// Validates a password change request body (the new password has the same requirements as
+    /// when registering)
+    fn validate(&self) -> Option<ValidationError> {
+        if !super::PASSWORD_VALIDATOR
+            .is_match(&self.new_password)
+            .unwrap()
+        {
+            Some(ErrorVariants::NewPasswordWeak.to_validation_error())
+        } else {
+            None
+        }
+    }
+}
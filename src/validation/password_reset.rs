@@ -0,0 +1,14 @@
+use crate::errors::validation::{auth::ErrorVariants, ValidationError};
+use crate::models::password_reset::PasswordResetComplete;
+
+impl super::Validate for PasswordResetComplete {
+    /// Validates a password reset request body (the new password has the same requirements as
+    /// when registering)
+    fn validate(&self) -> Option<ValidationError> {
+        if !super::PASSWORD_VALIDATOR.is_match(&self.password).unwrap() {
+            Some(ErrorVariants::PasswordWeak.to_validation_error())
+        } else {
+            None
+        }
+    }
+}
@@ -0,0 +1,26 @@
+use crate::errors::validation::{auth::ErrorVariants, ValidationError};
+use crate::models::user::UserUpdate;
+
+impl super::Validate for UserUpdate {
+    /// Validates a profile update request body, only the fields that are being changed are checked
+    /// (with the same rules as registration)
+    fn validate(&self) -> Option<ValidationError> {
+        let dn_len = self.displayname.as_ref().map(String::len);
+        let un_len = self.username.as_ref().map(String::len);
+        let bio_len = self.bio.as_ref().map(String::len);
+
+        Some(ErrorVariants::to_validation_error(
+            if matches!(dn_len, Some(len) if !(3..=128).contains(&len)) {
+                ErrorVariants::DisplaynameLength
+            } else if matches!(un_len, Some(len) if !(3..=128).contains(&len)) {
+                ErrorVariants::UsernameLength
+            } else if matches!(&self.username, Some(username) if username.contains('@')) {
+                ErrorVariants::UsernameInvalid
+            } else if matches!(bio_len, Some(len) if len > 512) {
+                ErrorVariants::BioLength
+            } else {
+                return None;
+            },
+        ))
+    }
+}
@@ -9,11 +9,13 @@ impl super::Validate for UserInsert {
         let em_len = self.email.len();
 
         Some(ErrorVariants::to_validation_error(
-            if dn_len <= 2 || dn_len > 128 {
+            if !(3..=128).contains(&dn_len) {
                 ErrorVariants::DisplaynameLength
-            } else if un_len <= 2 || un_len > 128 {
+            } else if !(3..=128).contains(&un_len) {
                 ErrorVariants::UsernameLength
-            } else if em_len < 6 || em_len > 256 {
+            } else if self.username.contains('@') {
+                ErrorVariants::UsernameInvalid
+            } else if !(6..=256).contains(&em_len) {
                 ErrorVariants::EmailLength
             } else if !super::EMAIL_VALIDATOR.is_match(&self.email).unwrap() {
                 ErrorVariants::EmailInvalid
@@ -0,0 +1,14 @@
+use crate::errors::validation::{auth::ErrorVariants, ValidationError};
+use crate::models::session::SessionUpdate;
+
+impl super::Validate for SessionUpdate {
+    /// Validates a session update request body
+    fn validate(&self) -> Option<ValidationError> {
+        match &self.device_name {
+            Some(name) if name.chars().count() > 64 => {
+                Some(ErrorVariants::DeviceNameLength.to_validation_error())
+            }
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,32 @@
+use crate::errors::validation::{todo::ErrorVariants, ValidationError};
+use crate::models::todo::{TodoInsert, TodoUpdate};
+
+/// Checks a todo's title and description, either of which can be left out of an update
+fn validate_fields(title: Option<&str>, description: Option<&str>) -> Option<ValidationError> {
+    let title_len = title.map(|t| t.chars().count());
+    let description_len = description.map(|d| d.chars().count());
+
+    Some(ErrorVariants::to_validation_error(
+        if matches!(title_len, Some(len) if !(1..=256).contains(&len)) {
+            ErrorVariants::TitleLength
+        } else if matches!(description_len, Some(len) if len > 2048) {
+            ErrorVariants::DescriptionLength
+        } else {
+            return None;
+        },
+    ))
+}
+
+impl super::Validate for TodoInsert {
+    /// Validates a todo creation request body
+    fn validate(&self) -> Option<ValidationError> {
+        validate_fields(Some(&self.title), Some(&self.description))
+    }
+}
+
+impl super::Validate for TodoUpdate {
+    /// Validates a todo update request body, only the fields that are being changed are checked
+    fn validate(&self) -> Option<ValidationError> {
+        validate_fields(self.title.as_deref(), self.description.as_deref())
+    }
+}